@@ -0,0 +1,276 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! In-memory cassettes.
+
+use std::{collections::HashMap, fmt, path::Path};
+
+use surf::{http::Method, Url};
+
+use crate::{
+    diff::{diff_requests, diff_responses},
+    format::{load_session, save_session, serialize_interaction},
+    Body,
+    Session,
+    VcrError,
+    VcrRequest,
+    VcrResponse,
+    WILDCARD_URL,
+};
+
+
+/// A set of recorded interactions.
+///
+/// Cassettes let you inspect, build, or convert recordings without going
+/// through a client; save one to disk to replay it with a [VcrMiddleware].
+///
+/// [VcrMiddleware]: crate::VcrMiddleware
+#[derive(Clone, Debug, Default)]
+pub struct Cassette {
+    session: Session,
+}
+
+impl Cassette {
+    /// Create an empty cassette.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read the cassette at the given path.
+    pub async fn load<P>(path: P) -> Result<Self, VcrError>
+        where P: AsRef<Path>,
+    {
+        Ok(Self { session: load_session(path.as_ref()).await? })
+    }
+
+    /// Write the cassette to the given path, replacing any file already there.
+    pub async fn save<P>(&self, path: P) -> Result<(), VcrError>
+        where P: AsRef<Path>,
+    {
+        save_session(path.as_ref(), &self.session).await
+    }
+
+    /// Add an interaction to the end of the cassette.
+    pub fn push(&mut self, request: VcrRequest, response: VcrResponse) {
+        self.session.push(request, response);
+    }
+
+    /// Add an interaction that responds to any request with the given method.
+    ///
+    /// The wildcard is only replayed if no other interaction matches the
+    /// request. It is recorded with the URL [WILDCARD_URL].
+    pub fn push_wildcard(&mut self, method: Method, response: VcrResponse) {
+        let request = VcrRequest {
+            method,
+            url: Url::parse(WILDCARD_URL).unwrap(),
+            effective_url: None,
+            label: None,
+            id: None,
+            headers: HashMap::new(),
+            body: Body::Str(String::new()),
+            body_hash: None,
+        };
+
+        self.session.push(request, response);
+    }
+
+    /// Iterate over the cassette's interactions in the order they were
+    /// recorded.
+    pub fn interactions(&self)
+    -> impl Iterator<Item = (&VcrRequest, &VcrResponse)> {
+        self.session.requests.iter().zip(self.session.responses.iter())
+    }
+
+    /// The recorded requests, in order.
+    ///
+    /// Clone them to use real requests as the inputs of property tests; for
+    /// example, with `proptest`:
+    ///
+    /// ```ignore
+    /// let cassette = Cassette::load("sessions/api.yml").await?;
+    /// let requests = proptest::sample::select(cassette.requests().to_vec());
+    ///
+    /// proptest!(|(req in requests)| {
+    ///     prop_assert!(parse_request(&req).is_ok());
+    /// });
+    /// ```
+    pub fn requests(&self) -> &[VcrRequest] {
+        &self.session.requests
+    }
+
+    /// Serialize the interaction at `index` exactly as it is recorded to a
+    /// cassette at `path`, whose extension determines the format: a YAML
+    /// document, an element of a JSON cassette's array, or a line of a JSON
+    /// Lines cassette. Compressed cassettes give the uncompressed text.
+    ///
+    /// This is useful for snapshot tests of what would be recorded, such as
+    /// checking that secrets are redacted.
+    ///
+    /// Returns [VcrError::InteractionOutOfBounds] if there is no interaction
+    /// at `index`.
+    pub fn to_document<P>(&self, index: usize, path: P)
+    -> Result<String, VcrError>
+        where P: AsRef<Path>,
+    {
+        let interaction = self.session.requests.get(index)
+            .zip(self.session.responses.get(index));
+
+        match interaction {
+            Some((req, resp)) =>
+                serialize_interaction(path.as_ref(), req.clone(), resp.clone()),
+            None => Err(VcrError::InteractionOutOfBounds {
+                index,
+                len: self.len(),
+            }),
+        }
+    }
+
+    /// List the method and URL of every recorded request, in order.
+    pub fn request_summary(&self) -> Vec<(Method, Url)> {
+        self.session.requests.iter()
+            .map(|req| (req.method, req.url.clone()))
+            .collect()
+    }
+
+    /// The number of interactions in the cassette.
+    pub fn len(&self) -> usize {
+        self.session.requests.len()
+    }
+
+    /// Returns true if the cassette has no interactions.
+    pub fn is_empty(&self) -> bool {
+        self.session.requests.is_empty()
+    }
+
+    /// Compare this cassette with another (e.g., a re-recording of it).
+    ///
+    /// Interactions are paired by their request's method and URL, in order; if
+    /// a cassette has several requests with the same method and URL, the
+    /// first is paired with the other cassette's first, and so on. Paired
+    /// interactions that differ are reported as modified, while unpaired
+    /// interactions were removed from this cassette or added by `other`.
+    pub fn diff(&self, other: &Cassette) -> CassetteDiff {
+        let mut diff = CassetteDiff::default();
+        let mut paired = vec![false; other.len()];
+
+        for (req, resp) in self.interactions() {
+            let found = other.interactions()
+                .enumerate()
+                .find(|(i, (other, _))| {
+                    ! paired[*i]
+                        && other.method == req.method
+                        && other.url == req.url
+                });
+
+            let (i, (other_req, other_resp)) = match found {
+                Some(found) => found,
+                None => {
+                    diff.removed.push((req.method, req.url.clone()));
+                    continue;
+                },
+            };
+            paired[i] = true;
+
+            if req == other_req && resp == other_resp {
+                continue;
+            }
+
+            diff.modified.push(ModifiedInteraction {
+                method: req.method,
+                url: req.url.clone(),
+                request_diff: if req == other_req {
+                    None
+                } else {
+                    Some(diff_requests(req, other_req))
+                },
+                response_diff: if resp == other_resp {
+                    None
+                } else {
+                    Some(diff_responses(resp, other_resp))
+                },
+            });
+        }
+
+        diff.added = other.session.requests.iter()
+            .zip(paired)
+            .filter(|(_, paired)| ! paired)
+            .map(|(req, _)| (req.method, req.url.clone()))
+            .collect();
+
+        diff
+    }
+}
+
+/// The differences between two cassettes, from [Cassette::diff].
+///
+/// Its [Display](fmt::Display) form lists the changes for logs, with the
+/// field-by-field differences of modified interactions.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CassetteDiff {
+    /// The method and URL of each interaction only in the other cassette.
+    pub added: Vec<(Method, Url)>,
+    /// The method and URL of each interaction only in this cassette.
+    pub removed: Vec<(Method, Url)>,
+    /// The interactions in both cassettes that differ.
+    pub modified: Vec<ModifiedInteraction>,
+}
+
+impl CassetteDiff {
+    /// Returns true if the cassettes have the same interactions.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.modified.is_empty()
+    }
+}
+
+impl fmt::Display for CassetteDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (method, url) in &self.removed {
+            writeln!(f, "removed: {} {}", method, url)?;
+        }
+
+        for (method, url) in &self.added {
+            writeln!(f, "added: {} {}", method, url)?;
+        }
+
+        for modified in &self.modified {
+            writeln!(f, "modified: {} {}", modified.method, modified.url)?;
+
+            if let Some(ref diff) = modified.request_diff {
+                writeln!(f, "  request:")?;
+                write_indented(f, diff)?;
+            }
+
+            if let Some(ref diff) = modified.response_diff {
+                writeln!(f, "  response:")?;
+                write_indented(f, diff)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn write_indented(f: &mut fmt::Formatter<'_>, text: &str) -> fmt::Result {
+    for line in text.lines() {
+        writeln!(f, "    {}", line)?;
+    }
+
+    Ok(())
+}
+
+/// An interaction that differs between two cassettes; see [CassetteDiff].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ModifiedInteraction {
+    pub method: Method,
+    pub url: Url,
+    /// The differences between the requests (as from [diff_requests]), if
+    /// they differ.
+    ///
+    /// [diff_requests]: crate::diff_requests
+    pub request_diff: Option<String>,
+    /// The differences between the responses, if they differ.
+    pub response_diff: Option<String>,
+}
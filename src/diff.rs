@@ -0,0 +1,180 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Human-readable comparisons of requests and responses.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    env,
+    fmt::{self, Write},
+    io::{self, IsTerminal},
+};
+
+use crate::{Body, VcrRequest, VcrResponse};
+
+
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const RESET: &str = "\x1b[0m";
+
+/// Produce a field-by-field diff of two requests.
+///
+/// Each line is prefixed with `-` if it is only in `left`, `+` if it is only in
+/// `right`, or a space if the two requests agree. When standard error is a
+/// terminal (and `NO_COLOR` is not set), the differing lines are colored.
+///
+/// ```
+/// # use surf_vcr::{diff_requests, Body, VcrRequest};
+/// # use surf::{http::Method, Url};
+/// let left = VcrRequest {
+///     method: Method::Get,
+///     url: Url::parse("https://example.com/widgets").unwrap(),
+///     effective_url: None,
+///     label: None,
+///     id: None,
+///     headers: Default::default(),
+///     body: Body::Str(String::new()),
+///     body_hash: None,
+/// };
+///
+/// let mut right = left.clone();
+/// right.method = Method::Post;
+///
+/// let diff = diff_requests(&left, &right);
+/// assert!(diff.contains("method: GET"));
+/// assert!(diff.contains("method: POST"));
+/// ```
+pub fn diff_requests(left: &VcrRequest, right: &VcrRequest) -> String {
+    let mut diff = Diff::new();
+
+    diff.field("method", &left.method, &right.method);
+    diff.field("url", &left.url, &right.url);
+
+    if left.effective_url.is_some() || right.effective_url.is_some() {
+        diff.field(
+            "effective_url",
+            &display_option(&left.effective_url),
+            &display_option(&right.effective_url),
+        );
+    }
+
+    if left.label.is_some() || right.label.is_some() {
+        diff.field(
+            "label",
+            &display_option(&left.label),
+            &display_option(&right.label),
+        );
+    }
+
+    diff.headers(&left.headers, &right.headers);
+    diff.field(
+        "body",
+        &display_body(&left.body),
+        &display_body(&right.body)
+    );
+
+    diff.out
+}
+
+/// Produce a field-by-field diff of two responses, formatted as
+/// [diff_requests] formats requests.
+pub(crate) fn diff_responses(left: &VcrResponse, right: &VcrResponse)
+-> String {
+    let mut diff = Diff::new();
+
+    diff.field("status", &left.status, &right.status);
+    diff.field(
+        "version",
+        &display_option(&left.version.map(|v| v.to_string())),
+        &display_option(&right.version.map(|v| v.to_string())),
+    );
+    diff.headers(&left.headers, &right.headers);
+    diff.field(
+        "body",
+        &display_body(&left.body),
+        &display_body(&right.body)
+    );
+
+    diff.out
+}
+
+struct Diff {
+    out: String,
+    colored: bool,
+}
+
+impl Diff {
+    fn new() -> Self {
+        let colored = env::var_os("NO_COLOR").is_none()
+            && io::stderr().is_terminal();
+
+        Self { out: String::new(), colored }
+    }
+
+    fn headers(
+        &mut self,
+        left: &HashMap<String, Vec<String>>,
+        right: &HashMap<String, Vec<String>>
+    ) {
+        self.line(' ', "headers:");
+
+        let names = left.keys()
+            .chain(right.keys())
+            .collect::<BTreeSet<_>>();
+
+        for name in names {
+            let label = format!("  {}", name);
+
+            match (left.get(name), right.get(name)) {
+                (Some(l), Some(r)) =>
+                    self.field(&label, &l.join(", "), &r.join(", ")),
+                (Some(l), None) =>
+                    self.line('-', &format!("{}: {}", label, l.join(", "))),
+                (None, Some(r)) =>
+                    self.line('+', &format!("{}: {}", label, r.join(", "))),
+                (None, None) => unreachable!(),
+            }
+        }
+    }
+
+    fn field<T>(&mut self, name: &str, left: &T, right: &T)
+        where T: fmt::Display + PartialEq + ?Sized
+    {
+        if left == right {
+            self.line(' ', &format!("{}: {}", name, left));
+        } else {
+            self.line('-', &format!("{}: {}", name, left));
+            self.line('+', &format!("{}: {}", name, right));
+        }
+    }
+
+    fn line(&mut self, prefix: char, text: &str) {
+        let color = match prefix {
+            '-' if self.colored => RED,
+            '+' if self.colored => GREEN,
+            _ => "",
+        };
+        let reset = if color.is_empty() { "" } else { RESET };
+
+        // Writing to a String cannot fail.
+        let _ = writeln!(self.out, "{}{} {}{}", color, prefix, text, reset);
+    }
+}
+
+fn display_option<T: fmt::Display>(val: &Option<T>) -> String {
+    match val {
+        Some(v) => v.to_string(),
+        None => "~".into(),
+    }
+}
+
+fn display_body(body: &Body) -> String {
+    match body {
+        Body::Str(s) => format!("{:?}", s),
+        Body::Bytes(b) => format!("{:?}", b),
+        Body::File { file } => format!("(file) {}", file.display()),
+        Body::Compressed { gzip } => format!("(gzip) {}", gzip),
+        Body::Json { json } => format!("(json) {}", json),
+    }
+}
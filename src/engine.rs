@@ -0,0 +1,1471 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The record and replay engine behind [VcrMiddleware].
+//!
+//! [VcrMiddleware]: crate::VcrMiddleware
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use async_std::{fs, sync::RwLock};
+
+use surf::{http::Method, Request, StatusCode, Url};
+
+#[cfg(feature = "graphql")]
+use crate::graphql;
+#[cfg(feature = "xml")]
+use crate::xml;
+use crate::{
+    diff::diff_requests,
+    format::{
+        append_interaction,
+        count_interactions,
+        load_session,
+        save_session,
+    },
+    matching::{
+        collapse_whitespace,
+        normalize_cookies,
+        normalize_header_whitespace,
+        normalize_media_types,
+        MatchOptions,
+    },
+    recording::RecordOptions,
+    replaying::ReplayOptions,
+    secrets,
+    template,
+    altered_headers,
+    closest_request,
+    hash_body,
+    headers_hash,
+    is_expired,
+    redirect_target,
+    take_delay_directive,
+    take_fault_directive,
+    trim_trailing_slash,
+    vary_headers,
+    Body,
+    BodyFault,
+    CassetteStore,
+    Expectations,
+    FailedAttempt,
+    FailureMode,
+    MatchOverrides,
+    MatchRules,
+    RedirectHop,
+    ReplayCounts,
+    Session,
+    VcrError,
+    VcrMode,
+    VcrRequest,
+    VcrResponse,
+    VcrStats,
+    CASSETTES,
+    EXPIRES_HEADER,
+    RECORDED,
+    MAX_REDIRECTS,
+    WILDCARD_URL,
+};
+
+
+/// The record and replay engine of a [VcrMiddleware], without surf's
+/// middleware pipeline.
+///
+/// Build a middleware as usual and take its core with
+/// [VcrMiddleware::into_core] to record and replay interactions through
+/// [VcrCore::record_interaction] and [VcrCore::lookup_interaction], without
+/// depending on surf's `Middleware` trait. A middleware dereferences to its
+/// core, and `VcrMiddleware::from` wraps a core back into a middleware.
+///
+/// Clones of a core share their state, as clones of a middleware do.
+///
+/// [VcrMiddleware]: crate::VcrMiddleware
+/// [VcrMiddleware::into_core]: crate::VcrMiddleware::into_core
+#[derive(Clone)]
+pub struct VcrCore {
+    pub(crate) mode: VcrMode,
+    pub(crate) file: PathBuf,
+    /// The key of the session the middleware replays in `CASSETTES`; this is
+    /// `file` unless the middleware replays a session of its own (see
+    /// [VcrMiddleware::with_variant] and [VcrMiddleware::with_load_transform]).
+    pub(crate) key: PathBuf,
+    pub(crate) match_options: MatchOptions,
+    pub(crate) record_options: RecordOptions,
+    pub(crate) replay_options: ReplayOptions,
+    pub(crate) failure_mode: FailureMode,
+    pub(crate) jitter_rng: Arc<Mutex<u64>>,
+    pub(crate) queued_responses: Arc<Mutex<VecDeque<VcrResponse>>>,
+    pub(crate) failed_attempt: Arc<Mutex<Option<FailedAttempt>>>,
+    pub(crate) expectations: Arc<Mutex<Expectations>>,
+    pub(crate) replays: Arc<Mutex<ReplayCounts>>,
+    /// The index of the next response to replay in [VcrMode::Stub].
+    pub(crate) next_stub: Arc<Mutex<usize>>,
+    /// The index of the interaction the next request must match if
+    /// strict ordering is set.
+    pub(crate) next_in_order: Arc<Mutex<usize>>,
+    /// The store that interactions are recorded to and replayed from, instead
+    /// of `file`.
+    pub(crate) store: Option<Arc<dyn CassetteStore>>,
+}
+
+/// The outcome of looking up a request to replay.
+pub(crate) enum Replay {
+    /// A recorded interaction matched the request.
+    Found(Replayed),
+    /// Nothing matched, so the default response is replayed.
+    Default(VcrResponse),
+    /// Nothing matched, and the request (as it was sent) should be recorded.
+    Miss(VcrRequest),
+}
+
+/// A recorded response to replay, with how it should be delivered.
+pub(crate) struct Replayed {
+    pub(crate) index: usize,
+    pub(crate) id: String,
+    pub(crate) response: VcrResponse,
+    /// How long the response takes to arrive.
+    pub(crate) delay: Duration,
+    /// The length of the body to deliver before the connection drops.
+    pub(crate) disconnect: Option<usize>,
+    pub(crate) redirects: Vec<RedirectHop>,
+}
+
+impl Drop for VcrCore {
+    fn drop(&mut self) {
+        // Only the last clone checks the expectations, and only if the user
+        // hasn't already done so.
+        if Arc::strong_count(&self.expectations) > 1 || thread::panicking() {
+            return;
+        }
+
+        let mut expectations = match self.expectations.lock() {
+            Ok(e) => e,
+            Err(_) => return,
+        };
+
+        if ! expectations.checked {
+            if let Err(e) = expectations.check() {
+                panic!("{}", e);
+            }
+        }
+    }
+}
+
+impl VcrCore {
+    /// Find the response to replay to a request sent by a client with the
+    /// given base URL.
+    ///
+    /// This follows the recorded redirects and applies the directives recorded
+    /// in the response, except for its delay and disconnection, which are left
+    /// to whoever delivers it.
+    pub(crate) async fn replay(
+        &self,
+        request: VcrRequest,
+        overrides: &MatchOverrides,
+        base_url: Option<&Url>
+    ) -> Result<Replay, VcrError> {
+        self.count_expected(&request);
+        self.find_replay(request, overrides, base_url, false).await
+    }
+
+    /// Find an expired interaction to replay to a request whose refresh failed
+    /// because the server couldn't be reached, if one matches.
+    pub(crate) async fn replay_expired(
+        &self,
+        request: VcrRequest,
+        overrides: &MatchOverrides,
+        base_url: Option<&Url>
+    ) -> Option<Replayed> {
+        match self.find_replay(request, overrides, base_url, true).await {
+            Ok(Replay::Found(replayed)) => {
+                // The request was counted as a miss when it was refreshed.
+                self.replays.lock().unwrap().misses -= 1;
+                Some(replayed)
+            },
+            _ => None,
+        }
+    }
+
+    /// Find the response to replay to a request, as [VcrCore::replay] does;
+    /// if `allow_expired` is set, expired interactions are replayed instead of
+    /// being refreshed.
+    async fn find_replay(
+        &self,
+        mut request: VcrRequest,
+        overrides: &MatchOverrides,
+        base_url: Option<&Url>,
+        allow_expired: bool
+    ) -> Result<Replay, VcrError> {
+        // We keep the request as sent in case we need to record it.
+        let original = if self.replay_options.record_on_replay_miss {
+            Some(request.clone())
+        } else {
+            None
+        };
+
+        self.rebase_url(&mut request, base_url).await;
+
+        let echoed = self.replay_options.echo_headers.iter()
+            .filter_map(|name| {
+                request.headers.get(name)
+                    .map(|values| (name.clone(), values.clone()))
+            })
+            .collect::<Vec<_>>();
+        let template_request = if self.replay_options.response_templating {
+            Some(request.clone())
+        } else {
+            None
+        };
+
+        self.prepare_lookup(&mut request);
+
+        let mut hop = if self.record_options.capture_redirect_chain {
+            Some(request.clone())
+        } else {
+            None
+        };
+
+        let (mut index, mut id, mut response) =
+            match (
+                self.lookup(request, overrides, allow_expired).await,
+                original
+            ) {
+                (Ok(found), _) => found,
+                (Err(VcrError::Lookup { .. }), Some(request)) => {
+                    if ! self.network_allowed(&request.url) {
+                        return Err(VcrError::NetworkForbidden(request.url));
+                    }
+
+                    return Ok(Replay::Miss(request));
+                },
+                (Err(VcrError::Lookup { .. }), None)
+                    if self.replay_options.default_response.is_some() =>
+                {
+                    let default = self.replay_options.default_response.clone();
+                    return Ok(Replay::Default(default.unwrap()));
+                },
+                (Err(e), _) => return Err(e),
+            };
+
+        // Follow the recorded redirects, as we did when recording.
+        let mut redirects = vec![];
+
+        while let Some(ref mut request) = hop {
+            let location = response.headers.iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case("location"))
+                .and_then(|(_, v)| v.last());
+            let target = redirect_target(
+                &request.url,
+                response.status,
+                location.map(String::as_str)
+            );
+
+            let (target, preserve) = match target {
+                Some(t) if redirects.len() < MAX_REDIRECTS => t,
+                _ => break,
+            };
+
+            redirects.push(RedirectHop {
+                url: std::mem::replace(&mut request.url, target),
+                status: response.status,
+            });
+
+            if ! preserve {
+                request.method = Method::Get;
+                request.body = Body::Str(String::new());
+            }
+
+            self.count_expected(request);
+
+            let found = self.lookup(request.clone(), overrides, allow_expired)
+                .await?;
+            index = found.0;
+            id = found.1;
+            response = found.2;
+        }
+
+        #[cfg(feature = "response-schema")]
+        self.validate_response(index, &response)?;
+
+        let delay = take_delay_directive(&mut response)
+            .unwrap_or_default()
+            + self.next_jitter().unwrap_or_default();
+
+        for (name, values) in echoed {
+            response.headers
+                .retain(|k, _| ! k.eq_ignore_ascii_case(&name));
+            response.headers.insert(name, values);
+        }
+
+        if let Some(ref request) = template_request {
+            if let Body::Str(ref mut body) = response.body {
+                *body = template::render(body, request);
+            }
+        }
+
+        let fault = take_fault_directive(&mut response);
+        response.headers
+            .retain(|k, _| ! k.eq_ignore_ascii_case(EXPIRES_HEADER));
+
+        let disconnect = match fault {
+            Some(BodyFault::Truncate(len)) => {
+                let mut bytes = response.body.as_bytes().to_vec();
+                bytes.truncate(len);
+                response.body = Body::Bytes(bytes);
+                None
+            },
+            Some(BodyFault::Disconnect(len)) => Some(len),
+            None => None,
+        };
+
+        self.replays.lock().unwrap().altered_headers
+            .extend(altered_headers(&response));
+
+        Ok(Replay::Found(Replayed {
+            index,
+            id,
+            response,
+            delay,
+            disconnect,
+            redirects,
+        }))
+    }
+
+    /// Find the next response to replay in [VcrMode::Stub].
+    pub(crate) async fn stub(&self, request: VcrRequest)
+    -> Result<Replayed, VcrError> {
+        self.count_expected(&request);
+        self.load_store().await?;
+
+        let (index, response) = self.next_stub().await?
+            .ok_or_else(|| VcrError::Lookup {
+                request: Box::new(Request::from(request)),
+                diff: None,
+            })?;
+
+        Ok(Replayed {
+            index,
+            id: self.interaction_id(index).await,
+            response,
+            delay: Duration::default(),
+            disconnect: None,
+            redirects: vec![],
+        })
+    }
+
+    /// Find the response to a request in [VcrMode::Programmatic].
+    pub(crate) fn program(&self, request: VcrRequest)
+    -> Result<VcrResponse, VcrError> {
+        self.count_expected(&request);
+
+        let queued = self.queued_responses.lock().unwrap().pop_front();
+        let response = queued.or_else(|| {
+            self.replay_options.responder.as_ref()
+                .map(|respond| respond(&request))
+        });
+
+        response.ok_or_else(|| VcrError::Lookup {
+            request: Box::new(Request::from(request)),
+            diff: None,
+        })
+    }
+
+    /// Record an interaction as the middleware would record a request and its
+    /// response.
+    ///
+    /// This allows recording requests that weren't sent through a surf client.
+    /// The middleware must be in [VcrMode::Record], or in [VcrMode::Replay]
+    /// with [VcrMiddleware::with_record_on_replay_miss] enabled.
+    ///
+    /// [VcrMiddleware::with_record_on_replay_miss]:
+    ///     crate::VcrMiddleware::with_record_on_replay_miss
+    pub async fn record_interaction(
+        &self,
+        request: VcrRequest,
+        response: VcrResponse
+    ) -> Result<(), VcrError> {
+        self.save_interaction(request, response, None).await
+    }
+
+    /// Record an interaction; if it's the first in the cassette, `base_url` is
+    /// recorded in the cassette's metadata.
+    pub(crate) async fn save_interaction(
+        &self,
+        mut request: VcrRequest,
+        mut response: VcrResponse,
+        base_url: Option<Url>
+    ) -> Result<(), VcrError> {
+        let recording = self.mode == VcrMode::Record
+            || (self.mode == VcrMode::Replay
+                && self.replay_options.record_on_replay_miss);
+
+        if ! recording {
+            return Err(VcrError::InvalidMode(format!("{:?}", self.mode)));
+        }
+
+        if let Some(ref filter) = self.record_options.record_status_filter {
+            if ! filter(response.status) {
+                return Ok(());
+            }
+        }
+
+        if let Some(ref extractor) = self.record_options.template_extractor {
+            extractor(&request, &mut response);
+        }
+
+        if let Some(ref modifier) = self.record_options.modify_request {
+            modifier(&mut request);
+        }
+
+        if let Some(ref modifier) = self.record_options.modify_response {
+            modifier(&mut response);
+        }
+
+        let ttl = self.record_options.interaction_ttl.as_ref()
+            .and_then(|ttl| ttl(&request));
+
+        if let Some(ttl) = ttl {
+            let expires = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                + ttl;
+
+            response.headers.insert(
+                EXPIRES_HEADER.into(),
+                vec![expires.as_secs().to_string()]
+            );
+        }
+
+        if request.label.is_none() {
+            if let Some(ref label) = self.record_options.label {
+                request.label = label(&request);
+            }
+        }
+
+        if self.record_options.force_binary_bodies {
+            request.body.make_binary();
+            response.body.make_binary();
+        }
+
+        if ! self.record_options.record_bodies {
+            request.body = Body::Str(String::new());
+            response.body = Body::Str(String::new());
+        }
+
+        let leak = secrets::find_secret(
+            &self.record_options.secret_patterns,
+            &request,
+            &response
+        );
+
+        if let Some(location) = leak {
+            return Err(VcrError::PotentialSecretLeak {
+                cassette: self.file.clone(),
+                location,
+            });
+        }
+
+        if self.record_options.record_body_hashes {
+            request.body_hash = Some(hash_body(&request.body));
+            response.body_hash = Some(hash_body(&response.body));
+        }
+
+        if self.record_options.record_interaction_ids {
+            request.id = Some(request.interaction_id());
+        }
+
+        let retryable = self.record_options.record_final_attempt
+            && (response.status == StatusCode::TooManyRequests
+                || response.status.is_server_error());
+        let attempt = if retryable { Some(request.clone()) } else { None };
+
+        let recorders = CASSETTES.read().await;
+        let mut lock = recorders.get(&self.file)
+            .ok_or_else(|| VcrError::MissingSession(self.file.clone()))?
+            .write().await;
+
+        if let Some(ref store) = self.store {
+            store.append(&request, &response).await?;
+            self.cache_recorded(&recorders, &mut lock, request, response, false)
+                .await;
+            return Ok(());
+        }
+
+        let path = match self.record_options.partial {
+            Some(ref partial) => {
+                // We record to a copy of the cassette until we're finished.
+                if ! partial.exists() && self.file.exists() {
+                    fs::copy(&self.file, partial).await?;
+                }
+                partial
+            },
+            None => &self.file,
+        };
+
+        let mut len = match fs::metadata(path).await {
+            Ok(meta) => meta.len(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        let empty = len == 0;
+
+        // A new cassette records the client's base URL, so it can be replayed
+        // by a client with another.
+        if let (0, Some(base_url)) = (len, base_url) {
+            let mut session = Session::default();
+            session.metadata.base_url = Some(base_url);
+
+            save_session(path, &session).await?;
+            len = fs::metadata(path).await?.len();
+        }
+
+        // If the previous attempt of this request failed and nothing has been
+        // recorded since, this is a retry and replaces that attempt.
+        let replace = self.failed_attempt.lock().unwrap().take()
+            .filter(|failed| failed.request == request && failed.end == len)
+            .map(|failed| failed.start);
+
+        let max = self.record_options.max_interactions;
+
+        if let (Some(max), None) = (max, replace) {
+            let counted = RECORDED.lock().unwrap().get(path).copied();
+            let recorded = match counted {
+                _ if empty => 0,
+                Some(count) => count,
+                None => count_interactions(path).await?,
+            };
+            RECORDED.lock().unwrap().insert(path.to_owned(), recorded);
+
+            if recorded >= max {
+                return if self.record_options.fail_over_max_interactions {
+                    Err(VcrError::TooManyInteractions {
+                        cassette: self.file.clone(),
+                        max,
+                    })
+                } else {
+                    Ok(())
+                };
+            }
+        }
+
+        self.cache_recorded(
+            &recorders,
+            &mut lock,
+            request.clone(),
+            response.clone(),
+            replace.is_some()
+        ).await;
+
+        let expected = if self.record_options.verify_round_trip {
+            Some((request.clone(), response.clone()))
+        } else {
+            None
+        };
+
+        self.externalize_body(&mut request.body).await?;
+        self.externalize_body(&mut response.body).await?;
+        self.compress_body(&mut request.body)?;
+        self.compress_body(&mut response.body)?;
+        self.structure_body(&mut request.body, true);
+        self.structure_body(&mut response.body, false);
+
+        let (start, end) =
+            append_interaction(
+                path,
+                request,
+                response,
+                replace,
+                &self.record_options.document_separator
+            ).await?;
+
+        if replace.is_none() {
+            if let Some(count) = RECORDED.lock().unwrap().get_mut(path) {
+                *count += 1;
+            }
+        }
+
+        if let Some((request, response)) = expected {
+            let session = load_session(path).await?;
+            let index = session.requests.len().saturating_sub(1);
+
+            let replayed = session.requests.last()
+                .zip(session.responses.last());
+
+            if replayed != Some((&request, &response)) {
+                return Err(VcrError::RoundTrip {
+                    cassette: self.file.clone(),
+                    index,
+                });
+            }
+        }
+        drop(lock);
+
+        if let Some(request) = attempt {
+            *self.failed_attempt.lock().unwrap() = Some(FailedAttempt {
+                request,
+                start,
+                end,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Add a recorded interaction to the sessions replayed from its cassette,
+    /// replacing their last interaction if `replace` is set.
+    async fn cache_recorded(
+        &self,
+        recorders: &HashMap<PathBuf, RwLock<Option<Session>>>,
+        lock: &mut Option<Session>,
+        request: VcrRequest,
+        response: VcrResponse,
+        replace: bool
+    ) {
+        let mut own = match recorders.get(&self.key) {
+            Some(own) if self.key != self.file => Some(own.write().await),
+            _ => None,
+        };
+
+        // If we're recording a replay's misses, we can replay them now too.
+        let sessions = [
+            lock.as_mut(),
+            own.as_deref_mut().and_then(Option::as_mut),
+        ];
+
+        for session in IntoIterator::into_iter(sessions).flatten() {
+            // The store will load it with the rest of its interactions.
+            if session.unloaded.is_some() {
+                continue;
+            }
+
+            if replace {
+                session.pop();
+            }
+            session.push(request.clone(), response.clone());
+        }
+    }
+
+    /// Move the given body to its own file if configured to do so.
+    async fn externalize_body(&self, body: &mut Body) -> Result<(), VcrError> {
+        let (dir, threshold) = match &self.record_options.external_bodies {
+            Some((dir, threshold)) => (dir, *threshold),
+            None => return Ok(()),
+        };
+
+        let bytes = body.as_bytes();
+        if bytes.is_empty() || bytes.len() < threshold {
+            return Ok(());
+        }
+
+        let ext = match &*body {
+            Body::Str(s) if serde_json::from_str::<serde_json::Value>(s)
+                .is_ok() => "json",
+            Body::Str(_) => "txt",
+            _ => "bin",
+        };
+
+        // Naming the file by its content lets identical bodies share a file;
+        // the hash is stable, so re-recording doesn't rename the files.
+        let stem = self.file.file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let name = dir.join(
+            format!("{}-{:016x}.{}", stem, hash_body(body), ext)
+        );
+
+        let base = self.file.parent().unwrap_or_else(|| Path::new(""));
+        fs::create_dir_all(base.join(dir)).await?;
+        fs::write(base.join(&name), bytes).await?;
+
+        *body = Body::File { file: name };
+        Ok(())
+    }
+
+    /// Compress the given body if configured to do so.
+    fn compress_body(&self, body: &mut Body) -> Result<(), VcrError> {
+        use std::io::Write;
+
+        let bytes = body.as_bytes();
+
+        match self.record_options.compress_bodies_over {
+            Some(threshold) if ! bytes.is_empty() && bytes.len() >= threshold
+                => {},
+            _ => return Ok(()),
+        }
+
+        let mut encoder = flate2::write::GzEncoder::new(
+            vec![],
+            flate2::Compression::default()
+        );
+        encoder.write_all(bytes)?;
+
+        *body = Body::Compressed { gzip: base64::encode(encoder.finish()?) };
+        Ok(())
+    }
+
+    /// Store a JSON object or array body as structured data; if `lossless`,
+    /// only if it serializes back to the same text.
+    fn structure_body(&self, body: &mut Body, lossless: bool) {
+        if ! self.record_options.structured_json_bodies {
+            return;
+        }
+
+        let json = match body {
+            Body::Str(s) => match serde_json::from_str(s) {
+                Ok(json @ serde_json::Value::Object(_))
+                    | Ok(json @ serde_json::Value::Array(_)) => json,
+                _ => return,
+            },
+            _ => return,
+        };
+
+        if ! lossless || json.to_string().as_bytes() == body.as_bytes() {
+            *body = Body::Json { json };
+        }
+    }
+
+    /// Find the recorded response to the given request, along with its index in
+    /// the cassette and the ID of its interaction.
+    async fn lookup(
+        &self,
+        request: VcrRequest,
+        overrides: &MatchOverrides,
+        allow_expired: bool
+    ) -> Result<(usize, String, VcrResponse), VcrError> {
+        if let Some((store, rules)) = self.unloaded_store().await {
+            if self.store_searchable(&rules, &request, overrides) {
+                let found = self.find_in_store(
+                    store.as_ref(),
+                    &rules,
+                    &request,
+                    overrides,
+                    allow_expired
+                ).await?;
+
+                if let Some(found) = found {
+                    return Ok(found);
+                }
+            }
+
+            // Relaxed matching and wildcards need every interaction, as does
+            // reporting the closest request to a miss.
+            self.load_store().await?;
+        }
+
+        let cassettes = CASSETTES.read().await;
+        let sessions = match cassettes.get(&self.key) {
+            Some(sessions) => sessions.read().await,
+            None => return Err(VcrError::MissingSession(self.file.clone())),
+        };
+
+        let session = sessions.as_ref()
+            .ok_or_else(|| VcrError::MissingSession(self.file.clone()))?;
+        let rules = &session.metadata.match_rules;
+
+        // Different hashes mean the bodies cannot be equal, but only exact
+        // body comparisons can rely on them.
+        let exact_bodies = self.record_options.record_bodies
+            && self.match_options.body_match_prefix.is_none()
+            && ! overrides.ignore_body
+            && ! self.match_options.bodyless_methods.contains(&request.method)
+            && ! self.match_options.whitespace_insensitive_body
+            && ! self.match_options.xml_bodies
+            && ! self.match_options.graphql_bodies
+            && self.match_options.body_matcher.is_none()
+            && self.match_options.request_normalizer.is_none()
+            && self.match_options.json_number_tolerance.is_none()
+            && rules.json_pointers.is_empty();
+        let body_hash = if exact_bodies {
+            Some(hash_body(&request.body))
+        } else {
+            None
+        };
+
+        // Likewise for headers, unless a recording's Vary header limits which
+        // headers are compared.
+        let exact_headers = ! overrides.ignore_headers
+            && ! self.match_options.cookie_aware_matching
+            && ! self.match_options.media_type_aware_matching
+            && self.match_options.presence_only_headers.is_empty()
+            && ! self.match_options.header_subset_matching
+            && self.match_options.request_normalizer.is_none()
+            && rules.ignore_headers.is_empty();
+        let header_hash = if exact_headers {
+            Some(headers_hash(&request.headers))
+        } else {
+            None
+        };
+
+        if self.replay_options.strict_order {
+            return self.lookup_in_order(session, request, overrides);
+        }
+
+        // Expired interactions are recorded again if we can reach the server.
+        let refresh = ! allow_expired
+            && self.mode == VcrMode::Replay
+            && self.replay_options.record_on_replay_miss
+            && self.network_allowed(&request.url);
+
+        let is_match = |pos: usize| {
+            let (x, resp) = (&session.requests[pos], &session.responses[pos]);
+
+            let hashes_differ = matches!(
+                (body_hash, session.body_hashes[pos]),
+                (Some(a), Some(b)) if a != b
+            );
+            let headers_differ = matches!(
+                header_hash, Some(h) if h != session.header_hashes[pos]
+            ) && vary_headers(resp).is_none();
+            let stale = refresh && is_expired(resp);
+
+            ! hashes_differ
+                && ! headers_differ
+                && ! stale
+                && self.matches(rules, overrides, x, resp, &request)
+        };
+
+        // When the method, URL, and headers must match exactly, only the
+        // requests indexed under the same hash can match.
+        let indexed = exact_headers
+            && ! self.match_options.match_on_effective_url
+            && ! self.match_options.ignore_trailing_slash
+            && rules.ignore_query_params.is_empty();
+
+        let pos = if indexed {
+            session.candidates(&request, exact_bodies).into_iter()
+                .find(|pos| is_match(*pos))
+        } else {
+            (0..session.requests.len()).find(|pos| is_match(*pos))
+        };
+
+        let pos = pos
+            .or_else(|| session.requests.iter().position(|x| {
+                x.method == request.method && x.url.as_str() == WILDCARD_URL
+            }));
+
+        let mut replays = self.replays.lock().unwrap();
+
+        match pos {
+            Some(pos) => {
+                replays.matched += 1;
+                replays.served.insert(pos);
+
+                // We'd have refreshed the interaction if we could.
+                if self.replay_options.record_on_replay_miss
+                    && is_expired(&session.responses[pos])
+                {
+                    replays.expired += 1;
+                }
+
+                Ok((
+                    pos,
+                    session.requests[pos].interaction_id(),
+                    session.responses[pos].clone()
+                ))
+            },
+            None => {
+                if ! allow_expired {
+                    replays.misses += 1;
+                }
+
+                let diff = closest_request(&session.requests, &request)
+                    .map(|closest| diff_requests(closest, &request));
+
+                Err(VcrError::Lookup {
+                    request: Box::new(Request::from(request)),
+                    diff,
+                })
+            },
+        }
+    }
+
+    /// Find the recorded response to a request, as the middleware would while
+    /// replaying.
+    ///
+    /// This allows replaying requests that aren't sent through a surf client.
+    /// Recorded redirects are not followed, and delays and faults recorded in
+    /// the response are not applied. The middleware must be in
+    /// [VcrMode::Replay].
+    pub async fn lookup_interaction(&self, mut request: VcrRequest)
+    -> Result<VcrResponse, VcrError> {
+        if self.mode != VcrMode::Replay {
+            return Err(VcrError::InvalidMode(format!("{:?}", self.mode)));
+        }
+
+        self.count_expected(&request);
+        self.prepare_lookup(&mut request);
+
+        self.lookup(request, &MatchOverrides::default(), false).await
+            .map(|(_, _, response)| response)
+    }
+
+    /// Whether a request can be looked up with [CassetteStore::find], which
+    /// only finds the requests with the same method, URL, and body.
+    fn store_searchable(
+        &self,
+        rules: &MatchRules,
+        request: &VcrRequest,
+        overrides: &MatchOverrides
+    ) -> bool {
+        let options = &self.match_options;
+
+        // Bodies aren't compared for bodyless methods, but such requests
+        // rarely have one.
+        let exact_body = if options.bodyless_methods.contains(&request.method)
+        {
+            request.body.as_bytes().is_empty()
+        } else {
+            ! overrides.ignore_body
+                && options.body_match_prefix.is_none()
+                && ! options.whitespace_insensitive_body
+                && ! options.xml_bodies
+                && ! options.graphql_bodies
+                && options.body_matcher.is_none()
+                && options.json_number_tolerance.is_none()
+                && rules.json_pointers.is_empty()
+        };
+
+        exact_body
+            && self.record_options.record_bodies
+            && ! options.match_on_effective_url
+            && ! options.ignore_trailing_slash
+            && options.request_normalizer.is_none()
+            && rules.ignore_query_params.is_empty()
+            && ! self.replay_options.strict_order
+            // A transformed copy of the session has to be matched in memory.
+            && self.key == self.file
+    }
+
+    /// Find the recorded response to a request among the interactions its
+    /// store finds for it; see [VcrCore::lookup].
+    async fn find_in_store(
+        &self,
+        store: &dyn CassetteStore,
+        rules: &MatchRules,
+        request: &VcrRequest,
+        overrides: &MatchOverrides,
+        allow_expired: bool
+    ) -> Result<Option<(usize, String, VcrResponse)>, VcrError> {
+        let refresh = ! allow_expired
+            && self.mode == VcrMode::Replay
+            && self.replay_options.record_on_replay_miss
+            && self.network_allowed(&request.url);
+
+        let found = store.find(request).await?.into_iter()
+            .find(|(_, recorded, resp)| {
+                ! (refresh && is_expired(resp))
+                    && self.matches(rules, overrides, recorded, resp, request)
+            });
+
+        let (pos, recorded, response) = match found {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+
+        let mut replays = self.replays.lock().unwrap();
+        replays.matched += 1;
+        replays.served.insert(pos);
+
+        if self.replay_options.record_on_replay_miss && is_expired(&response) {
+            replays.expired += 1;
+        }
+
+        Ok(Some((pos, recorded.interaction_id(), response)))
+    }
+
+    /// The store that the interactions of the middleware's session are still
+    /// only in, if they haven't been loaded, and the session's match rules.
+    async fn unloaded_store(&self)
+    -> Option<(Arc<dyn CassetteStore>, MatchRules)> {
+        let cassettes = CASSETTES.read().await;
+        let session = cassettes.get(&self.key)?.read().await;
+
+        session.as_ref().and_then(|session| {
+            session.unloaded.clone()
+                .map(|store| (store, session.metadata.match_rules.clone()))
+        })
+    }
+
+    /// Load the interactions of the middleware's session from the store
+    /// they're in, if they haven't been already.
+    pub(crate) async fn load_store(&self) -> Result<(), VcrError> {
+        let cassettes = CASSETTES.read().await;
+        let mut session = match cassettes.get(&self.key) {
+            Some(session) => session.write().await,
+            None => return Ok(()),
+        };
+
+        let session = match session.as_mut() {
+            Some(session) => session,
+            None => return Ok(()),
+        };
+
+        if let Some(store) = session.unloaded.clone() {
+            for (request, response) in store.load().await? {
+                session.push(request, response);
+            }
+            session.unloaded = None;
+        }
+
+        Ok(())
+    }
+
+    /// If the cassette recorded the base URL of its client, replace the base
+    /// URL of the replaying client with it in the request's URL.
+    async fn rebase_url(&self, request: &mut VcrRequest, live: Option<&Url>) {
+        let live = match live {
+            Some(live) => live,
+            None => return,
+        };
+
+        let cassettes = CASSETTES.read().await;
+        let session = match cassettes.get(&self.key) {
+            Some(session) => session.read().await,
+            None => return,
+        };
+
+        let recorded = session.as_ref()
+            .and_then(|session| session.metadata.base_url.as_ref());
+
+        if let Some(recorded) = recorded.filter(|url| *url != live) {
+            let rebased = request.url.as_str()
+                .strip_prefix(live.as_str())
+                .and_then(|path| {
+                    Url::parse(&format!("{}{}", recorded, path)).ok()
+                });
+
+            if let Some(url) = rebased {
+                request.url = url;
+            }
+        }
+    }
+
+    /// Apply the changes made to recorded requests to a request that will be
+    /// looked up.
+    fn prepare_lookup(&self, request: &mut VcrRequest) {
+        if let Some(ref modifier) = self.record_options.modify_request {
+            modifier(request);
+        }
+
+        if self.record_options.force_binary_bodies {
+            request.body.make_binary();
+        }
+    }
+
+    /// Match a request against the next interaction of the cassette only; see
+    /// [VcrMiddleware::with_strict_order].
+    fn lookup_in_order(
+        &self,
+        session: &Session,
+        request: VcrRequest,
+        overrides: &MatchOverrides
+    ) -> Result<(usize, String, VcrResponse), VcrError> {
+        let rules = &session.metadata.match_rules;
+
+        let mut next = self.next_in_order.lock().unwrap();
+        let mut replays = self.replays.lock().unwrap();
+
+        let recorded = session.requests.get(*next);
+        let matched = recorded.map(|recorded| {
+            let response = &session.responses[*next];
+            self.matches(rules, overrides, recorded, response, &request)
+        });
+
+        if matched == Some(true) {
+            let index = *next;
+            *next += 1;
+
+            replays.matched += 1;
+            replays.served.insert(index);
+            Ok((
+                index,
+                session.requests[index].interaction_id(),
+                session.responses[index].clone()
+            ))
+        } else {
+            replays.misses += 1;
+
+            Err(VcrError::Lookup {
+                diff: recorded.map(|r| diff_requests(r, &request)),
+                request: Box::new(Request::from(request)),
+            })
+        }
+    }
+
+    /// The ID of the interaction at `index` in the cassette.
+    async fn interaction_id(&self, index: usize) -> String {
+        let cassettes = CASSETTES.read().await;
+        let session = match cassettes.get(&self.key) {
+            Some(session) => session.read().await,
+            None => return String::new(),
+        };
+
+        session.as_ref()
+            .and_then(|session| session.requests.get(index))
+            .map(VcrRequest::interaction_id)
+            .unwrap_or_default()
+    }
+
+    /// Take the next response to replay in [VcrMode::Stub], along with its
+    /// index in the cassette.
+    async fn next_stub(&self)
+    -> Result<Option<(usize, VcrResponse)>, VcrError> {
+        let cassettes = CASSETTES.read().await;
+        let session = match cassettes.get(&self.key) {
+            Some(session) => session.read().await,
+            None => return Err(VcrError::MissingSession(self.file.clone())),
+        };
+        let session = session.as_ref()
+            .ok_or_else(|| VcrError::MissingSession(self.file.clone()))?;
+
+        let mut next = self.next_stub.lock().unwrap();
+        let mut replays = self.replays.lock().unwrap();
+
+        match session.responses.get(*next) {
+            Some(response) => {
+                let index = *next;
+                *next += 1;
+
+                replays.matched += 1;
+                replays.served.insert(index);
+                Ok(Some((index, response.clone())))
+            },
+            None => {
+                replays.misses += 1;
+                Ok(None)
+            },
+        }
+    }
+
+    #[cfg(feature = "response-schema")]
+    fn validate_response(&self, index: usize, response: &VcrResponse)
+    -> Result<(), VcrError> {
+        let schema = &self.replay_options.response_schema;
+
+        let (validator, body) = match (schema, &response.body) {
+            (Some(validator), Body::Str(body)) => (validator, body),
+            _ => return Ok(()),
+        };
+
+        let body = match serde_json::from_str(body) {
+            Ok(body) => body,
+            Err(_) => return Ok(()),
+        };
+
+        let errors = validator.iter_errors(&body)
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>();
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(VcrError::SchemaViolation {
+                cassette: self.file.clone(),
+                index,
+                errors,
+            })
+        }
+    }
+
+    /// Check that the expected number of requests were made, and save the
+    /// recording if [VcrMiddleware::with_atomic_recording] is enabled.
+    ///
+    /// See [VcrMiddleware::with_expected_count].
+    ///
+    /// [VcrMiddleware::with_atomic_recording]:
+    ///     crate::VcrMiddleware::with_atomic_recording
+    /// [VcrMiddleware::with_expected_count]:
+    ///     crate::VcrMiddleware::with_expected_count
+    pub fn finish(&self) -> Result<(), VcrError> {
+        if let Some(ref partial) = self.record_options.partial {
+            if partial.exists() {
+                std::fs::rename(partial, &self.file)?;
+
+                let mut recorded = RECORDED.lock().unwrap();
+                match recorded.remove(partial) {
+                    Some(count) => recorded.insert(self.file.clone(), count),
+                    None => recorded.remove(&self.file),
+                };
+            }
+        }
+
+        self.expectations.lock().unwrap().check()
+    }
+
+    /// Get statistics of the requests replayed from the cassette so far.
+    ///
+    /// The statistics are only collected while replaying; clones of the
+    /// middleware share them.
+    pub async fn stats(&self) -> VcrStats {
+        let interactions = match CASSETTES.read().await.get(&self.key) {
+            Some(session) => match &*session.read().await {
+                Some(session) => match session.unloaded {
+                    Some(ref store) => store.count().await.unwrap_or(0),
+                    None => session.requests.len(),
+                },
+                None => 0,
+            },
+            None => 0,
+        };
+
+        let replays = self.replays.lock().unwrap();
+
+        VcrStats {
+            cassette: self.file.clone(),
+            matched: replays.matched,
+            unused: interactions.saturating_sub(replays.served.len()),
+            misses: replays.misses,
+            expired: replays.expired,
+            altered_headers: replays.altered_headers.iter().cloned().collect(),
+        }
+    }
+
+    pub(crate) fn count_expected(&self, request: &VcrRequest) {
+        let mut expectations = self.expectations.lock().unwrap();
+
+        for count in expectations.counts.iter_mut() {
+            if (count.matcher)(request) {
+                count.actual += 1;
+            }
+        }
+    }
+
+    /// Returns true if a request to `url` may be sent to the server.
+    fn network_allowed(&self, url: &Url) -> bool {
+        ! self.replay_options.forbid_network || url.host_str()
+            .map(|host| self.replay_options.allowed_hosts.iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(host)))
+            .unwrap_or(false)
+    }
+
+    /// Add a response to return to the next request in
+    /// [VcrMode::Programmatic].
+    ///
+    /// Since clones share their state, you can push responses to a clone after
+    /// registering the middleware with a client.
+    pub fn push_response(&self, response: VcrResponse) {
+        self.queued_responses.lock().unwrap().push_back(response);
+    }
+
+    /// Choose the jitter for the next replayed response.
+    pub(crate) fn next_jitter(&self) -> Option<Duration> {
+        let max = self.replay_options.jitter?.as_nanos() as u64;
+        let mut state = self.jitter_rng.lock().unwrap();
+
+        // SplitMix64; we don't need a strong generator.
+        *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = *state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+
+        Some(Duration::from_nanos(z % max.saturating_add(1)))
+    }
+
+    /// Determine whether a recorded request matches the request being sent.
+    fn matches(
+        &self,
+        rules: &MatchRules,
+        overrides: &MatchOverrides,
+        recorded: &VcrRequest,
+        response: &VcrResponse,
+        request: &VcrRequest
+    ) -> bool {
+        match &self.match_options.request_normalizer {
+            Some(normalize) => {
+                let mut recorded = recorded.clone();
+                let mut request = request.clone();
+                normalize(&mut recorded);
+                normalize(&mut request);
+
+                self.requests_match(
+                    rules,
+                    overrides,
+                    &recorded,
+                    response,
+                    &request
+                )
+            },
+            None => self.requests_match(
+                rules,
+                overrides,
+                recorded,
+                response,
+                request
+            ),
+        }
+    }
+
+    fn requests_match(
+        &self,
+        rules: &MatchRules,
+        overrides: &MatchOverrides,
+        recorded: &VcrRequest,
+        response: &VcrResponse,
+        request: &VcrRequest
+    ) -> bool {
+        let url = if self.match_options.match_on_effective_url {
+            recorded.effective_url.as_ref().unwrap_or(&recorded.url)
+        } else {
+            &recorded.url
+        };
+
+        let urls_match = if self.match_options.ignore_trailing_slash {
+            rules.urls_match(
+                &trim_trailing_slash(url),
+                &trim_trailing_slash(&request.url)
+            )
+        } else {
+            rules.urls_match(url, &request.url)
+        };
+
+        recorded.method == request.method
+            && urls_match
+            && (overrides.ignore_headers || self.headers_match(
+                rules,
+                vary_headers(response).as_deref(),
+                &recorded.headers,
+                &request.headers
+            ))
+            && (overrides.ignore_body
+                || self.match_options.bodyless_methods.contains(&request.method)
+                || self.bodies_match(rules, &recorded.body, &request.body))
+    }
+
+    /// Compare request headers; if `vary` is set, only the headers it names are
+    /// compared.
+    fn headers_match(
+        &self,
+        rules: &MatchRules,
+        vary: Option<&[String]>,
+        recorded: &HashMap<String, Vec<String>>,
+        headers: &HashMap<String, Vec<String>>
+    ) -> bool {
+        let options = &self.match_options;
+
+        let is_present = |name: &String| headers.iter()
+            .any(|(k, v)| {
+                k.eq_ignore_ascii_case(name)
+                    && v.iter().any(|v| ! v.trim().is_empty())
+            });
+
+        if ! options.presence_only_headers.iter().all(is_present) {
+            return false;
+        }
+
+        let normalize = |headers: &HashMap<String, Vec<String>>| {
+            let headers = match vary {
+                Some(names) => headers.iter()
+                    .filter(|(k, _)| names.contains(&k.to_ascii_lowercase()))
+                    .map(|(k, v)| (k.to_ascii_lowercase(), v.clone()))
+                    .collect(),
+                None => headers.clone(),
+            };
+
+            let headers = headers.into_iter()
+                .filter(|(k, _)| {
+                    let name = k.to_ascii_lowercase();
+                    ! options.presence_only_headers.contains(&name)
+                })
+                .collect::<HashMap<_, _>>();
+
+            let headers = if options.cookie_aware_matching {
+                normalize_cookies(&headers)
+            } else {
+                headers
+            };
+
+            let headers = if options.media_type_aware_matching {
+                normalize_media_types(&headers)
+            } else {
+                headers
+            };
+
+            normalize_header_whitespace(headers)
+        };
+
+        let (recorded, headers) = (normalize(recorded), normalize(headers));
+
+        if options.header_subset_matching {
+            let headers = headers.into_iter()
+                .filter(|(k, _)| {
+                    recorded.keys().any(|name| name.eq_ignore_ascii_case(k))
+                })
+                .collect();
+
+            return rules.headers_match(&recorded, &headers);
+        }
+
+        rules.headers_match(&recorded, &headers)
+    }
+
+    fn bodies_match(&self, rules: &MatchRules, recorded: &Body, body: &Body)
+    -> bool {
+        if ! self.record_options.record_bodies {
+            return true;
+        }
+
+        let options = &self.match_options;
+
+        if let Some(matcher) = &options.body_matcher {
+            return matcher(recorded, body);
+        }
+
+        #[cfg(feature = "xml")]
+        if options.xml_bodies && options.body_match_prefix.is_none() {
+            let matched = xml::bodies_match(
+                recorded.as_bytes(),
+                body.as_bytes()
+            );
+
+            if let Some(matched) = matched {
+                return matched;
+            }
+        }
+
+        #[cfg(feature = "graphql")]
+        if options.graphql_bodies && options.body_match_prefix.is_none() {
+            let matched = graphql::bodies_match(
+                recorded.as_bytes(),
+                body.as_bytes()
+            );
+
+            if let Some(matched) = matched {
+                return matched;
+            }
+        }
+
+        match options.body_match_prefix {
+            Some(len) => {
+                let (recorded, body) = (recorded.as_bytes(), body.as_bytes());
+
+                recorded[..len.min(recorded.len())]
+                    == body[..len.min(body.len())]
+            },
+            None => match (recorded, body) {
+                (Body::Str(recorded), Body::Str(body))
+                    if options.whitespace_insensitive_body =>
+                {
+                    rules.bodies_match(
+                        &Body::Str(collapse_whitespace(recorded)),
+                        &Body::Str(collapse_whitespace(body)),
+                        options.json_number_tolerance
+                    )
+                },
+                _ => rules.bodies_match(
+                    recorded,
+                    body,
+                    options.json_number_tolerance
+                ),
+            },
+        }
+    }
+}
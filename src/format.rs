@@ -0,0 +1,1330 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Cassette file formats.
+//!
+//! The format of a cassette is determined by its extension: `.json` files are
+//! JSON cassettes, `.jsonl` files are JSON Lines cassettes, and everything else
+//! is YAML. Each format is available only if its feature (`yaml` or `json`) is
+//! enabled.
+//!
+//! A YAML cassette is a series of YAML documents, each holding one interaction,
+//! so we record by appending to the file. A JSON cassette is a single array of
+//! interactions, so we record by rewriting the file. A JSON Lines cassette has
+//! one interaction per line, so we again record by appending.
+//!
+//! A cassette whose name ends with `.zst` (e.g. `api.yml.zst`) is compressed
+//! with zstd if the `zstd` feature is enabled; its format is determined by the
+//! rest of its name. Compressed cassettes are decompressed into memory as
+//! they're read, and rewritten whenever an interaction is recorded.
+//!
+//! The layout of the interactions is versioned by the cassette's metadata;
+//! older layouts are upgraded as they're loaded.
+
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
+
+use async_std::fs;
+use futures::stream::{self, Stream};
+
+use crate::{
+    decompress_body,
+    flatten_json_body,
+    CASSETTE_VERSION,
+    load_external_body,
+    CassetteMetadata,
+    SerdeWrapper,
+    Session,
+    VcrError,
+    VcrRequest,
+    VcrResponse,
+};
+
+
+enum Format {
+    #[cfg(feature = "yaml")]
+    Yaml,
+    #[cfg(feature = "json")]
+    Json,
+    #[cfg(feature = "json")]
+    JsonLines,
+}
+
+impl Format {
+    /// Determine the format of the cassette at the given path.
+    fn of(path: &Path) -> Result<Self, VcrError> {
+        if is_zstd(path) {
+            #[cfg(feature = "zstd")]
+            return Self::of(&path.with_extension(""));
+            #[cfg(not(feature = "zstd"))]
+            return Err(VcrError::UnsupportedFormat(path.to_owned()));
+        }
+
+        let ext = path.extension()
+            .and_then(OsStr::to_str)
+            .map(str::to_ascii_lowercase);
+
+        match ext.as_deref() {
+            #[cfg(feature = "json")]
+            Some("json") => Ok(Self::Json),
+            #[cfg(feature = "json")]
+            Some("jsonl") => Ok(Self::JsonLines),
+            #[allow(unreachable_patterns)]
+            Some("json") | Some("jsonl") =>
+                Err(VcrError::UnsupportedFormat(path.to_owned())),
+            #[cfg(feature = "yaml")]
+            _ => Ok(Self::Yaml),
+            #[allow(unreachable_patterns)]
+            _ => Err(VcrError::UnsupportedFormat(path.to_owned())),
+        }
+    }
+
+    /// Serialize an entire session.
+    fn serialize(&self, session: &Session) -> Result<String, VcrError> {
+        match self {
+            #[cfg(feature = "yaml")]
+            Self::Yaml => yaml::serialize_session(session),
+            #[cfg(feature = "json")]
+            Self::Json => json::serialize_session(session),
+            #[cfg(feature = "json")]
+            Self::JsonLines => jsonl::serialize_session(session),
+        }
+    }
+
+    /// Serialize a single interaction as it's written to a cassette.
+    fn serialize_interaction(
+        &self,
+        request: VcrRequest,
+        response: VcrResponse,
+    ) -> Result<String, VcrError> {
+        match self {
+            #[cfg(feature = "yaml")]
+            Self::Yaml => yaml::serialize_interaction(request, response),
+            #[cfg(feature = "json")]
+            Self::Json => json::serialize_interaction(request, response),
+            #[cfg(feature = "json")]
+            Self::JsonLines => jsonl::serialize_interaction(request, response),
+        }
+    }
+
+    /// Parse the text of a cassette that has been read into memory.
+    #[cfg(feature = "zstd")]
+    async fn load_str(&self, text: &str, loader: &mut SessionLoader<'_>)
+    -> Result<(), VcrError> {
+        match self {
+            #[cfg(feature = "yaml")]
+            Self::Yaml => yaml::load_str(text, loader).await,
+            #[cfg(feature = "json")]
+            Self::Json => json::load_str(text, loader).await,
+            #[cfg(feature = "json")]
+            Self::JsonLines => jsonl::load_str(text, loader).await,
+        }
+    }
+
+    /// Add an interaction to the text of a cassette that has been read into
+    /// memory, returning the offset of the new interaction.
+    #[cfg(feature = "zstd")]
+    fn append_str(
+        &self,
+        text: &mut String,
+        request: VcrRequest,
+        response: VcrResponse,
+        replace: Option<u64>,
+        #[cfg_attr(not(feature = "yaml"), allow(unused_variables))]
+        separator: &str,
+    ) -> Result<u64, VcrError> {
+        match self {
+            #[cfg(feature = "yaml")]
+            Self::Yaml => {
+                let doc = yaml::document(request, response, separator)?;
+                Ok(append_text(text, &doc, replace))
+            },
+            #[cfg(feature = "json")]
+            Self::Json => {
+                let start = text.len() as u64;
+                *text = json::append_str(text, request, response, replace)?;
+                Ok(start)
+            },
+            #[cfg(feature = "json")]
+            Self::JsonLines => {
+                let line = jsonl::serialize_interaction(request, response)?;
+                Ok(append_text(text, &line, replace))
+            },
+        }
+    }
+}
+
+/// Create the directory that will hold the cassette at `path`, and any missing
+/// parents.
+async fn create_parent_dir(path: &Path) -> Result<(), VcrError> {
+    match path.parent() {
+        Some(dir) if ! dir.as_os_str().is_empty() => {
+            fs::create_dir_all(dir).await?;
+            Ok(())
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Returns true if the cassette at `path` is compressed with zstd.
+fn is_zstd(path: &Path) -> bool {
+    path.extension()
+        .and_then(OsStr::to_str)
+        .map(|ext| ext.eq_ignore_ascii_case("zst"))
+        .unwrap_or(false)
+}
+
+/// Returns true if the file at `path` is named like a cassette, whether or not
+/// its format's feature is enabled.
+pub(crate) fn is_cassette(path: &Path) -> bool {
+    let path = if is_zstd(path) {
+        path.with_extension("")
+    } else {
+        path.to_owned()
+    };
+
+    let ext = path.extension()
+        .and_then(OsStr::to_str)
+        .map(str::to_ascii_lowercase);
+
+    matches!(ext.as_deref(), Some("yml" | "yaml" | "json" | "jsonl"))
+}
+
+/// Add a serialized interaction to the end of `text`, first removing the
+/// interaction at `replace` and everything after it.
+#[cfg(feature = "zstd")]
+fn append_text(text: &mut String, interaction: &str, replace: Option<u64>)
+-> u64 {
+    if let Some(start) = replace {
+        text.truncate(start as usize);
+    }
+
+    let start = text.len() as u64;
+    text.push_str(interaction);
+    start
+}
+
+/// Read and parse the cassette at the given path.
+pub(crate) async fn load_session(recording: &Path)
+-> Result<Session, VcrError> {
+    let mut loader = SessionLoader {
+        session: Session::default(),
+        base: recording.parent().unwrap_or_else(|| Path::new("")),
+        documents: 0,
+    };
+
+    let format = Format::of(recording)?;
+
+    #[cfg(feature = "zstd")]
+    if is_zstd(recording) {
+        let text = compressed::read(recording).await?;
+        format.load_str(&text, &mut loader).await?;
+        return Ok(loader.session);
+    }
+
+    match format {
+        #[cfg(feature = "yaml")]
+        Format::Yaml => yaml::load(recording, &mut loader).await?,
+        #[cfg(feature = "json")]
+        Format::Json => json::load(recording, &mut loader).await?,
+        #[cfg(feature = "json")]
+        Format::JsonLines => jsonl::load(recording, &mut loader).await?,
+    }
+
+    Ok(loader.session)
+}
+
+/// Open the cassette at the given path to be replayed.
+///
+/// Large YAML cassettes are indexed rather than loaded; see
+/// [yaml::INCREMENTAL_LOAD_THRESHOLD].
+pub(crate) async fn open_session(recording: &Path)
+-> Result<Session, VcrError> {
+    #[cfg(feature = "yaml")]
+    if matches!(Format::of(recording)?, Format::Yaml) && ! is_zstd(recording) {
+        let len = fs::metadata(recording).await?.len();
+
+        if len >= yaml::INCREMENTAL_LOAD_THRESHOLD {
+            return yaml::index(recording).await;
+        }
+    }
+
+    load_session(recording).await
+}
+
+/// Count the interactions in the cassette at the given path without
+/// deserializing them.
+pub(crate) async fn count_interactions(recording: &Path)
+-> Result<usize, VcrError> {
+    let format = Format::of(recording)?;
+
+    // A compressed cassette must be read in full regardless.
+    if is_zstd(recording) {
+        return Ok(load_session(recording).await?.requests.len());
+    }
+
+    match format {
+        #[cfg(feature = "yaml")]
+        Format::Yaml => yaml::count(recording).await,
+        #[cfg(feature = "json")]
+        Format::Json => json::count(recording).await,
+        #[cfg(feature = "json")]
+        Format::JsonLines => jsonl::count(recording).await,
+    }
+}
+
+/// Write an entire session to the given path in its format, replacing any file
+/// already there.
+pub(crate) async fn save_session(path: &Path, session: &Session)
+-> Result<(), VcrError> {
+    let doc = Format::of(path)?.serialize(session)?;
+    create_parent_dir(path).await?;
+
+    #[cfg(feature = "zstd")]
+    if is_zstd(path) {
+        return compressed::write(path, &doc).await;
+    }
+
+    fs::write(path, doc.as_bytes()).await?;
+    Ok(())
+}
+
+/// Serialize an interaction as it would be written to the cassette at `path`,
+/// in the cassette's format and uncompressed.
+pub(crate) fn serialize_interaction(
+    path: &Path,
+    request: VcrRequest,
+    response: VcrResponse,
+) -> Result<String, VcrError> {
+    Format::of(path)?.serialize_interaction(request, response)
+}
+
+/// The line written before each YAML document.
+pub(crate) const DOCUMENT_SEPARATOR: &str = "---";
+
+/// Returns true if `line` starts a new YAML document: `---`, optionally
+/// followed by a comment.
+pub(crate) fn is_document_separator(line: &str) -> bool {
+    match line.strip_prefix(DOCUMENT_SEPARATOR) {
+        Some("") => true,
+        Some(rest) =>
+            rest.starts_with(' ') && rest.trim_start().starts_with('#'),
+        None => false,
+    }
+}
+
+/// Add an interaction to the end of a cassette file.
+///
+/// If `replace` is set, the interaction replaces the last one in the file,
+/// which starts at that offset. YAML documents are preceded by `separator`.
+/// Returns the offsets of the start and end of the new interaction.
+///
+/// The start of an interaction in a compressed cassette is its offset in the
+/// decompressed text; the end is the length of the file.
+pub(crate) async fn append_interaction(
+    path: &Path,
+    request: VcrRequest,
+    response: VcrResponse,
+    replace: Option<u64>,
+    #[cfg_attr(not(feature = "yaml"), allow(unused_variables))]
+    separator: &str,
+) -> Result<(u64, u64), VcrError> {
+    let format = Format::of(path)?;
+    create_parent_dir(path).await?;
+
+    #[cfg(feature = "zstd")]
+    if is_zstd(path) {
+        let mut text = if file_len(path).await? == 0 {
+            String::new()
+        } else {
+            compressed::read(path).await?
+        };
+
+        let start = format.append_str(
+            &mut text,
+            request,
+            response,
+            replace,
+            separator
+        )?;
+        compressed::write(path, &text).await?;
+
+        return Ok((start, file_len(path).await?));
+    }
+
+    match format {
+        #[cfg(feature = "yaml")]
+        Format::Yaml =>
+            yaml::append(path, request, response, replace, separator).await,
+        #[cfg(feature = "json")]
+        Format::Json => json::append(path, request, response, replace).await,
+        #[cfg(feature = "json")]
+        Format::JsonLines =>
+            jsonl::append(path, request, response, replace).await,
+    }
+}
+
+/// Builds a session from a cassette's documents.
+struct SessionLoader<'a> {
+    session: Session,
+    /// The directory that external bodies are relative to.
+    base: &'a Path,
+    documents: usize,
+}
+
+impl SessionLoader<'_> {
+    /// Add the next document of the cassette; `parse` deserializes it, and
+    /// returns `None` if the document is empty.
+    async fn add<F>(&mut self, parse: F) -> Result<(), VcrError>
+        where F: Fn(bool) -> Result<Option<Document>, VcrError>,
+    {
+        // The first document may describe the cassette rather than an
+        // interaction.
+        let (request, response) = match parse(self.documents == 0)? {
+            None => return Ok(()),
+            Some(Document::Metadata(meta)) => {
+                self.documents += 1;
+                migrate(meta.version)?;
+                self.session.metadata = meta;
+                return Ok(());
+            },
+            Some(Document::Interaction(interaction)) => *interaction,
+        };
+
+        self.documents += 1;
+
+        let (req, resp) = resolve_interaction(request, response, self.base)
+            .await?;
+
+        self.session.push(req, resp);
+        Ok(())
+    }
+}
+
+/// Check that we can read a cassette with the given layout version.
+fn migrate(version: u32) -> Result<(), VcrError> {
+    match version {
+        // No layout has changed yet; when one does, the interactions of
+        // older cassettes will be upgraded as they're loaded.
+        CASSETTE_VERSION => Ok(()),
+        _ => Err(VcrError::UnsupportedVersion(version)),
+    }
+}
+
+/// Unwrap a deserialized interaction, and load its external, compressed, and
+/// structured bodies.
+async fn resolve_interaction(
+    request: SerdeWrapper,
+    response: SerdeWrapper,
+    base: &Path,
+) -> Result<(VcrRequest, VcrResponse), VcrError> {
+    let mut req = match request {
+        SerdeWrapper::Request(r) => r,
+        doc => return Err(VcrError::UnexpectedDocument {
+            expected: "Request",
+            found: doc.kind(),
+        }),
+    };
+    let mut resp = match response {
+        SerdeWrapper::Response(r) => r,
+        doc => return Err(VcrError::UnexpectedDocument {
+            expected: "Response",
+            found: doc.kind(),
+        }),
+    };
+
+    load_external_body(&mut req.body, base).await?;
+    load_external_body(&mut resp.body, base).await?;
+    decompress_body(&mut req.body)?;
+    decompress_body(&mut resp.body)?;
+    flatten_json_body(&mut req.body);
+    flatten_json_body(&mut resp.body);
+
+    Ok((req, resp))
+}
+
+/// Read the interactions of the cassette at the given path one at a time.
+///
+/// The first error ends the stream.
+pub(crate) fn read_interactions(recording: PathBuf)
+-> impl Stream<Item = Result<(VcrRequest, VcrResponse), VcrError>>
+    + Send + Unpin
+{
+    let reader = InteractionReader {
+        base: recording.parent().unwrap_or_else(|| Path::new("")).to_owned(),
+        recording,
+        source: None,
+        documents: 0,
+        done: false,
+    };
+
+    Box::pin(stream::unfold(reader, |mut reader| async move {
+        if reader.done {
+            return None;
+        }
+
+        let next = reader.next().await;
+        reader.done = ! matches!(next, Some(Ok(_)));
+        next.map(|interaction| (interaction, reader))
+    }))
+}
+
+struct InteractionReader {
+    recording: PathBuf,
+    base: PathBuf,
+    /// The open cassette; we open it when the first interaction is read.
+    source: Option<Source>,
+    documents: usize,
+    done: bool,
+}
+
+enum Source {
+    #[cfg(feature = "yaml")]
+    Yaml(yaml::Documents),
+    #[cfg(feature = "json")]
+    Json(std::vec::IntoIter<serde_json::Value>),
+    #[cfg(feature = "json")]
+    JsonLines(Lines),
+    /// The interactions of a cassette that had to be read in full.
+    #[cfg(feature = "zstd")]
+    Loaded(std::vec::IntoIter<(VcrRequest, VcrResponse)>),
+}
+
+/// The lines of a cassette file.
+type Lines = async_std::io::Lines<async_std::io::BufReader<fs::File>>;
+
+/// Open the cassette at `recording` to read it a line at a time.
+async fn read_lines(recording: &Path) -> Result<Lines, VcrError> {
+    use async_std::io::prelude::BufReadExt;
+
+    let file = fs::File::open(recording).await?;
+    Ok(async_std::io::BufReader::new(file).lines())
+}
+
+impl InteractionReader {
+    async fn next(&mut self)
+    -> Option<Result<(VcrRequest, VcrResponse), VcrError>> {
+        if self.source.is_none() {
+            match self.open().await {
+                Ok(source) => self.source = Some(source),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+
+        #[cfg(feature = "zstd")]
+        if let Some(Source::Loaded(interactions)) = self.source.as_mut() {
+            return interactions.next().map(Ok);
+        }
+
+        loop {
+            let first = self.documents == 0;
+
+            let doc = match self.source.as_mut().unwrap() {
+                #[cfg(feature = "yaml")]
+                Source::Yaml(docs) => match docs.next().await? {
+                    Ok(doc) => yaml::parse(&doc, first),
+                    Err(e) => Err(e),
+                },
+                #[cfg(feature = "json")]
+                Source::Json(docs) => json::parse(&docs.next()?, first),
+                #[cfg(feature = "json")]
+                Source::JsonLines(lines) => {
+                    use async_std::stream::StreamExt;
+
+                    match lines.next().await? {
+                        Ok(line) => jsonl::parse(&line, first),
+                        Err(e) => Err(e.into()),
+                    }
+                },
+                #[cfg(feature = "zstd")]
+                Source::Loaded(_) => unreachable!(),
+            };
+
+            let (request, response) = match doc {
+                Ok(None) => continue,
+                Ok(Some(Document::Metadata(meta))) => {
+                    self.documents += 1;
+
+                    match migrate(meta.version) {
+                        Ok(()) => continue,
+                        Err(e) => return Some(Err(e)),
+                    }
+                },
+                Ok(Some(Document::Interaction(interaction))) => *interaction,
+                Err(e) => return Some(Err(e)),
+            };
+
+            self.documents += 1;
+            return Some(resolve_interaction(request, response, &self.base)
+                .await);
+        }
+    }
+
+    async fn open(&self) -> Result<Source, VcrError> {
+        let format = Format::of(&self.recording)?;
+
+        #[cfg(feature = "zstd")]
+        if is_zstd(&self.recording) {
+            let session = load_session(&self.recording).await?;
+            let interactions = session.requests.into_iter()
+                .zip(session.responses)
+                .collect::<Vec<_>>();
+
+            return Ok(Source::Loaded(interactions.into_iter()));
+        }
+
+        match format {
+            #[cfg(feature = "yaml")]
+            Format::Yaml => Ok(Source::Yaml(
+                yaml::Documents::open(&self.recording).await?
+            )),
+            #[cfg(feature = "json")]
+            Format::Json => Ok(Source::Json(
+                json::read(&self.recording).await?.into_iter()
+            )),
+            #[cfg(feature = "json")]
+            Format::JsonLines => Ok(Source::JsonLines(
+                read_lines(&self.recording).await?
+            )),
+        }
+    }
+}
+
+enum Document {
+    Metadata(CassetteMetadata),
+    Interaction(Box<(SerdeWrapper, SerdeWrapper)>),
+}
+
+/// Returns the length of the file at `path`, or 0 if it doesn't exist.
+async fn file_len(path: &Path) -> Result<u64, VcrError> {
+    match fs::metadata(path).await {
+        Ok(meta) => Ok(meta.len()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(feature = "yaml")]
+pub(crate) mod yaml {
+    use super::*;
+
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    use async_std::prelude::*;
+
+    use crate::store::{store_key, CassetteStore, StoreFuture};
+
+    /// Cassettes at least this large are parsed a document at a time as
+    /// they're read, so their text is never held in memory in full.
+    ///
+    /// A middleware replaying such a cassette doesn't load its interactions:
+    /// it records where each of the cassette's documents is, and looks a
+    /// request up by reading only the documents recorded for its method and
+    /// URL. The interactions are loaded (a document at a time) only when a
+    /// lookup needs them all, as for a [CassetteStore].
+    pub(crate) const INCREMENTAL_LOAD_THRESHOLD: u64 = 1 << 20;
+
+    /// Serialize an interaction as a YAML document.
+    pub(crate) fn serialize_interaction(
+        request: VcrRequest,
+        response: VcrResponse
+    ) -> Result<String, VcrError> {
+        Ok(serde_yaml::to_string(
+            &(SerdeWrapper::Request(request), SerdeWrapper::Response(response))
+        )?)
+    }
+
+    /// Serialize an interaction as a YAML document that starts with
+    /// `separator`.
+    pub(super) fn document(
+        request: VcrRequest,
+        response: VcrResponse,
+        separator: &str,
+    ) -> Result<String, VcrError> {
+        let doc = serialize_interaction(request, response)?;
+
+        if separator == DOCUMENT_SEPARATOR {
+            return Ok(doc);
+        }
+
+        let body = doc.strip_prefix(DOCUMENT_SEPARATOR)
+            .unwrap_or(&doc)
+            .trim_start_matches('\n');
+        Ok(format!("{}\n{}", separator, body))
+    }
+
+    pub(super) fn serialize_session(session: &Session)
+    -> Result<String, VcrError> {
+        let mut doc = String::new();
+
+        if session.metadata != CassetteMetadata::default() {
+            doc.push_str(&serde_yaml::to_string(
+                &SerdeWrapper::Metadata(session.metadata.clone())
+            )?);
+        }
+
+        let interactions = session.requests.iter().zip(&session.responses);
+
+        for (req, resp) in interactions {
+            doc.push_str(&serialize_interaction(req.clone(), resp.clone())?);
+        }
+
+        Ok(doc)
+    }
+
+    /// Parse a cassette file; large files are read incrementally (see
+    /// [INCREMENTAL_LOAD_THRESHOLD]).
+    pub(super) async fn load(recording: &Path, loader: &mut SessionLoader<'_>)
+    -> Result<(), VcrError> {
+        let len = fs::metadata(recording).await?.len();
+
+        if len < INCREMENTAL_LOAD_THRESHOLD {
+            load_str(&fs::read_to_string(recording).await?, loader).await?;
+        } else {
+            let mut docs = Documents::open(recording).await?;
+
+            while let Some(replay) = docs.next().await {
+                let replay = replay?;
+                loader.add(|first| parse(&replay, first)).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Parse the text of an entire cassette.
+    pub(super) async fn load_str(replays: &str, loader: &mut SessionLoader<'_>)
+    -> Result<(), VcrError> {
+        let mut replay = String::new();
+
+        for line in replays.lines() {
+            if is_document_separator(line) {
+                loader.add(|first| parse(&replay, first)).await?;
+                replay.clear();
+            } else {
+                replay.push_str(line);
+                replay.push('\n');
+            }
+        }
+
+        loader.add(|first| parse(&replay, first)).await
+    }
+
+    /// Index the documents of a cassette, returning a session that holds only
+    /// its metadata; see [INCREMENTAL_LOAD_THRESHOLD].
+    pub(super) async fn index(recording: &Path) -> Result<Session, VcrError> {
+        use async_std::io::{prelude::BufReadExt, BufReader};
+
+        let mut reader = BufReader::new(fs::File::open(recording).await?);
+        let mut session = Session::default();
+        let index = DocumentIndex {
+            recording: recording.to_owned(),
+            documents: Default::default(),
+        };
+
+        let mut line = String::new();
+        let mut doc = String::new();
+        let mut offset = 0;
+        let mut start = 0;
+        let mut first = true;
+
+        loop {
+            line.clear();
+            let len = reader.read_line(&mut line).await? as u64;
+
+            let text = line.strip_suffix('\n')
+                .map(|line| line.strip_suffix('\r').unwrap_or(line))
+                .unwrap_or(&line);
+
+            if len > 0 && ! is_document_separator(text) {
+                doc.push_str(text);
+                doc.push('\n');
+                offset += len;
+                continue;
+            }
+
+            match parse(&doc, first)? {
+                None => {},
+                Some(Document::Metadata(meta)) => {
+                    migrate(meta.version)?;
+                    session.metadata = meta;
+                    first = false;
+                },
+                Some(Document::Interaction(interaction)) => {
+                    let request = match interaction.0 {
+                        SerdeWrapper::Request(ref request) => request,
+                        ref doc => return Err(VcrError::UnexpectedDocument {
+                            expected: "Request",
+                            found: doc.kind(),
+                        }),
+                    };
+
+                    index.add(request, start, offset - start);
+                    first = false;
+                },
+            }
+
+            if len == 0 {
+                break;
+            }
+
+            doc.clear();
+            offset += len;
+            start = offset;
+        }
+
+        session.unloaded = Some(Arc::new(index));
+        Ok(session)
+    }
+
+    /// Where the interactions of a large cassette are; see [index].
+    struct DocumentIndex {
+        recording: PathBuf,
+        documents: Mutex<Extents>,
+    }
+
+    /// The offsets and lengths of the documents of a cassette's interactions.
+    #[derive(Default)]
+    struct Extents {
+        /// The positions of the interactions in the cassette and the extents of
+        /// their documents, by the methods and URLs of their requests.
+        by_head: HashMap<String, Vec<(usize, u64, u64)>>,
+        count: usize,
+    }
+
+    impl DocumentIndex {
+        /// Record the extent of the next interaction's document.
+        fn add(&self, request: &VcrRequest, offset: u64, len: u64) {
+            let mut documents = self.documents.lock().unwrap();
+            let pos = documents.count;
+
+            documents.by_head.entry(head_key(request))
+                .or_default()
+                .push((pos, offset, len));
+            documents.count += 1;
+        }
+    }
+
+    /// The key of a request's documents in a [DocumentIndex].
+    fn head_key(request: &VcrRequest) -> String {
+        format!("{} {}", request.method, request.url)
+    }
+
+    impl CassetteStore for DocumentIndex {
+        fn path(&self) -> &Path {
+            &self.recording
+        }
+
+        fn load(&self) -> StoreFuture<'_, Vec<(VcrRequest, VcrResponse)>> {
+            Box::pin(async move {
+                let session = load_session(&self.recording).await?;
+                let interactions = session.requests.into_iter()
+                    .zip(session.responses);
+
+                Ok(interactions.collect())
+            })
+        }
+
+        fn append<'a>(
+            &'a self,
+            request: &'a VcrRequest,
+            response: &'a VcrResponse
+        ) -> StoreFuture<'a, ()> {
+            Box::pin(async move {
+                let (start, end) = append(
+                    &self.recording,
+                    request.clone(),
+                    response.clone(),
+                    None,
+                    DOCUMENT_SEPARATOR
+                ).await?;
+
+                // The document is indexed without its separator line.
+                let start = start + DOCUMENT_SEPARATOR.len() as u64 + 1;
+                self.add(request, start, end - start);
+                Ok(())
+            })
+        }
+
+        fn find<'a>(&'a self, request: &'a VcrRequest)
+        -> StoreFuture<'a, Vec<(usize, VcrRequest, VcrResponse)>> {
+            use async_std::io::SeekFrom;
+
+            Box::pin(async move {
+                let documents = self.documents.lock().unwrap().by_head
+                    .get(&head_key(request))
+                    .cloned()
+                    .unwrap_or_default();
+
+                if documents.is_empty() {
+                    return Ok(vec![]);
+                }
+
+                let key = store_key(request);
+                let base = self.recording.parent()
+                    .unwrap_or_else(|| Path::new(""));
+                let mut file = fs::File::open(&self.recording).await?;
+                let mut found = vec![];
+
+                for (pos, offset, len) in documents {
+                    let mut bytes = vec![0; len as usize];
+                    file.seek(SeekFrom::Start(offset)).await?;
+                    file.read_exact(&mut bytes).await?;
+
+                    let text = String::from_utf8(bytes).map_err(|e| {
+                        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+                    })?;
+                    let doc = text.lines()
+                        .flat_map(|line| [line, "\n"])
+                        .collect::<String>();
+
+                    let (req, resp) = match parse(&doc, false)? {
+                        Some(Document::Interaction(interaction)) =>
+                            *interaction,
+                        _ => continue,
+                    };
+                    let (req, resp) = resolve_interaction(req, resp, base)
+                        .await?;
+
+                    if store_key(&req) == key {
+                        found.push((pos, req, resp));
+                    }
+                }
+
+                Ok(found)
+            })
+        }
+
+        fn count(&self) -> StoreFuture<'_, usize> {
+            let count = self.documents.lock().unwrap().count;
+            Box::pin(async move { Ok(count) })
+        }
+    }
+
+    /// Reads the documents of a cassette a line at a time.
+    pub(super) struct Documents {
+        lines: Lines,
+        finished: bool,
+    }
+
+    impl Documents {
+        pub(super) async fn open(recording: &Path) -> Result<Self, VcrError> {
+            Ok(Self {
+                lines: read_lines(recording).await?,
+                finished: false,
+            })
+        }
+
+        /// Read the text of the next document.
+        pub(super) async fn next(&mut self)
+        -> Option<Result<String, VcrError>> {
+            if self.finished {
+                return None;
+            }
+
+            let mut doc = String::new();
+
+            while let Some(line) = self.lines.next().await {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(e) => return Some(Err(e.into())),
+                };
+
+                if is_document_separator(&line) {
+                    return Some(Ok(doc));
+                }
+
+                doc.push_str(&line);
+                doc.push('\n');
+            }
+
+            self.finished = true;
+            Some(Ok(doc))
+        }
+    }
+
+    pub(super) async fn count(recording: &Path) -> Result<usize, VcrError> {
+        let mut lines = read_lines(recording).await?;
+
+        let mut count = 0;
+        let mut in_document = false;
+        let mut first = true;
+
+        while let Some(line) = lines.next().await {
+            let line = line?;
+
+            if is_document_separator(&line) {
+                in_document = false;
+            } else if ! in_document && ! line.trim().is_empty() {
+                in_document = true;
+
+                if ! (first && line.starts_with("Metadata:")) {
+                    count += 1;
+                }
+                first = false;
+            }
+        }
+
+        Ok(count)
+    }
+
+    pub(super) fn parse(replay: &str, first: bool)
+    -> Result<Option<Document>, VcrError> {
+        if replay.trim().is_empty() {
+            return Ok(None);
+        }
+
+        if first {
+            if let Ok(SerdeWrapper::Metadata(meta)) =
+                serde_yaml::from_str(replay)
+            {
+                return Ok(Some(Document::Metadata(meta)));
+            }
+        }
+
+        Ok(Some(Document::Interaction(Box::new(serde_yaml::from_str(replay)?))))
+    }
+
+    pub(super) async fn append(
+        path: &Path,
+        request: VcrRequest,
+        response: VcrResponse,
+        replace: Option<u64>,
+        separator: &str,
+    ) -> Result<(u64, u64), VcrError> {
+        let doc = document(request, response, separator)?;
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path).await?;
+
+        let start = match replace {
+            Some(start) => {
+                file.set_len(start).await?;
+                start
+            },
+            None => file_len(path).await?,
+        };
+
+        // Each record is a new YAML document.
+        file.write_all(doc.as_bytes()).await?;
+
+        Ok((start, start + doc.len() as u64))
+    }
+}
+
+#[cfg(feature = "json")]
+mod json {
+    use super::*;
+
+    use serde_json::Value;
+
+    pub(super) fn serialize_session(session: &Session)
+    -> Result<String, VcrError> {
+        let mut docs = vec![];
+
+        if session.metadata != CassetteMetadata::default() {
+            docs.push(serde_json::to_value(
+                SerdeWrapper::Metadata(session.metadata.clone())
+            )?);
+        }
+
+        let interactions = session.requests.iter().zip(&session.responses);
+
+        for (req, resp) in interactions {
+            docs.push(interaction_value(req.clone(), resp.clone())?);
+        }
+
+        serialize(&docs)
+    }
+
+    pub(super) async fn load(recording: &Path, loader: &mut SessionLoader<'_>)
+    -> Result<(), VcrError> {
+        load_docs(read(recording).await?, loader).await
+    }
+
+    #[cfg(feature = "zstd")]
+    pub(super) async fn load_str(text: &str, loader: &mut SessionLoader<'_>)
+    -> Result<(), VcrError> {
+        load_docs(parse_docs(text)?, loader).await
+    }
+
+    async fn load_docs(docs: Vec<Value>, loader: &mut SessionLoader<'_>)
+    -> Result<(), VcrError> {
+        for doc in docs {
+            loader.add(|first| parse(&doc, first)).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Read the documents of a cassette; an empty file has none.
+    pub(super) async fn read(recording: &Path) -> Result<Vec<Value>, VcrError> {
+        parse_docs(&fs::read_to_string(recording).await?)
+    }
+
+    fn parse_docs(text: &str) -> Result<Vec<Value>, VcrError> {
+        if text.trim().is_empty() {
+            Ok(vec![])
+        } else {
+            Ok(serde_json::from_str(text)?)
+        }
+    }
+
+    pub(super) async fn count(recording: &Path) -> Result<usize, VcrError> {
+        let docs = read(recording).await?;
+
+        let has_metadata = docs.first()
+            .map(|doc| doc.get("Metadata").is_some())
+            .unwrap_or(false);
+
+        Ok(docs.len() - has_metadata as usize)
+    }
+
+    pub(super) fn parse(doc: &Value, first: bool)
+    -> Result<Option<Document>, VcrError> {
+        if first {
+            if let Ok(SerdeWrapper::Metadata(meta)) =
+                serde_json::from_value(doc.clone())
+            {
+                return Ok(Some(Document::Metadata(meta)));
+            }
+        }
+
+        let interaction = serde_json::from_value(doc.clone())?;
+        Ok(Some(Document::Interaction(Box::new(interaction))))
+    }
+
+    pub(super) async fn append(
+        path: &Path,
+        request: VcrRequest,
+        response: VcrResponse,
+        replace: Option<u64>,
+    ) -> Result<(u64, u64), VcrError> {
+        let start = file_len(path).await?;
+
+        let text = if start == 0 {
+            String::new()
+        } else {
+            fs::read_to_string(path).await?
+        };
+
+        let text = append_str(&text, request, response, replace)?;
+        fs::write(path, &text).await?;
+
+        Ok((start, text.len() as u64))
+    }
+
+    /// Add an interaction to the text of a cassette, returning the new text.
+    pub(super) fn append_str(
+        text: &str,
+        request: VcrRequest,
+        response: VcrResponse,
+        replace: Option<u64>,
+    ) -> Result<String, VcrError> {
+        let mut docs = parse_docs(text)?;
+
+        if replace.is_some() {
+            docs.pop();
+        }
+
+        docs.push(interaction_value(request, response)?);
+        serialize(&docs)
+    }
+
+    /// Serialize an interaction as an element of the cassette's array.
+    pub(super) fn serialize_interaction(
+        request: VcrRequest,
+        response: VcrResponse
+    ) -> Result<String, VcrError> {
+        let mut text = serde_json::to_string_pretty(
+            &interaction_value(request, response)?
+        )?;
+        text.push('\n');
+        Ok(text)
+    }
+
+    fn interaction_value(request: VcrRequest, response: VcrResponse)
+    -> Result<Value, VcrError> {
+        Ok(serde_json::to_value(
+            (SerdeWrapper::Request(request), SerdeWrapper::Response(response))
+        )?)
+    }
+
+    fn serialize(docs: &[Value]) -> Result<String, VcrError> {
+        let mut text = serde_json::to_string_pretty(docs)?;
+        text.push('\n');
+        Ok(text)
+    }
+}
+
+#[cfg(feature = "json")]
+mod jsonl {
+    use super::*;
+
+    use async_std::prelude::*;
+    use serde::{Deserialize, Serialize};
+
+    /// A line of a JSON Lines cassette.
+    #[derive(Deserialize, Serialize)]
+    struct Interaction {
+        request: VcrRequest,
+        response: VcrResponse,
+    }
+
+    pub(super) fn serialize_session(session: &Session)
+    -> Result<String, VcrError> {
+        let mut text = String::new();
+
+        if session.metadata != CassetteMetadata::default() {
+            text.push_str(&serde_json::to_string(
+                &SerdeWrapper::Metadata(session.metadata.clone())
+            )?);
+            text.push('\n');
+        }
+
+        let interactions = session.requests.iter().zip(&session.responses);
+
+        for (req, resp) in interactions {
+            text.push_str(&serialize_interaction(req.clone(), resp.clone())?);
+        }
+
+        Ok(text)
+    }
+
+    pub(super) async fn load(recording: &Path, loader: &mut SessionLoader<'_>)
+    -> Result<(), VcrError> {
+        let mut lines = read_lines(recording).await?;
+
+        while let Some(line) = lines.next().await {
+            let line = line?;
+            loader.add(|first| parse(&line, first)).await?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "zstd")]
+    pub(super) async fn load_str(text: &str, loader: &mut SessionLoader<'_>)
+    -> Result<(), VcrError> {
+        for line in text.lines() {
+            loader.add(|first| parse(line, first)).await?;
+        }
+
+        Ok(())
+    }
+
+    pub(super) async fn count(recording: &Path) -> Result<usize, VcrError> {
+        let mut lines = read_lines(recording).await?;
+
+        let mut count = 0;
+        let mut first = true;
+
+        while let Some(line) = lines.next().await {
+            let line = line?;
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            if ! (first && line.trim_start().starts_with("{\"Metadata\"")) {
+                count += 1;
+            }
+            first = false;
+        }
+
+        Ok(count)
+    }
+
+    pub(super) fn parse(line: &str, first: bool)
+    -> Result<Option<Document>, VcrError> {
+        if line.trim().is_empty() {
+            return Ok(None);
+        }
+
+        if first {
+            if let Ok(SerdeWrapper::Metadata(meta)) =
+                serde_json::from_str(line)
+            {
+                return Ok(Some(Document::Metadata(meta)));
+            }
+        }
+
+        let Interaction { request, response } = serde_json::from_str(line)?;
+
+        Ok(Some(Document::Interaction(Box::new((
+            SerdeWrapper::Request(request),
+            SerdeWrapper::Response(response),
+        )))))
+    }
+
+    pub(super) async fn append(
+        path: &Path,
+        request: VcrRequest,
+        response: VcrResponse,
+        replace: Option<u64>,
+    ) -> Result<(u64, u64), VcrError> {
+        let line = serialize_interaction(request, response)?;
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path).await?;
+
+        let start = match replace {
+            Some(start) => {
+                file.set_len(start).await?;
+                start
+            },
+            None => file_len(path).await?,
+        };
+
+        // Each record is a new line; the lines before it are left untouched.
+        file.write_all(line.as_bytes()).await?;
+
+        Ok((start, start + line.len() as u64))
+    }
+
+    /// Serialize an interaction as a line, including its newline.
+    pub(super) fn serialize_interaction(
+        request: VcrRequest,
+        response: VcrResponse
+    ) -> Result<String, VcrError> {
+        let mut line = serde_json::to_string(
+            &Interaction { request, response }
+        )?;
+        line.push('\n');
+        Ok(line)
+    }
+}
+
+#[cfg(feature = "zstd")]
+mod compressed {
+    use super::*;
+
+    /// Read and decompress a cassette.
+    pub(super) async fn read(recording: &Path) -> Result<String, VcrError> {
+        let bytes = zstd::stream::decode_all(
+            fs::read(recording).await?.as_slice()
+        )?;
+
+        String::from_utf8(bytes).map_err(|e| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, e).into()
+        })
+    }
+
+    /// Compress and write a cassette, replacing any file already there.
+    pub(super) async fn write(recording: &Path, text: &str)
+    -> Result<(), VcrError> {
+        let bytes = zstd::stream::encode_all(text.as_bytes(), 0)?;
+        fs::write(recording, bytes).await?;
+        Ok(())
+    }
+}
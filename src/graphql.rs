@@ -0,0 +1,140 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Semantic comparison of GraphQL request bodies.
+
+use serde_json::{Map, Value};
+
+
+/// Compare two GraphQL requests by their operation, or return `None` if either
+/// is not a GraphQL request.
+///
+/// The queries are compared by their tokens and the variables as JSON, so
+/// formatting, comments, and the order of variables are ignored.
+pub(crate) fn bodies_match(recorded: &[u8], body: &[u8]) -> Option<bool> {
+    Some(Operation::parse(recorded)? == Operation::parse(body)?)
+}
+
+#[derive(Debug, PartialEq)]
+struct Operation {
+    query: Vec<String>,
+    name: Option<String>,
+    variables: Value,
+}
+
+impl Operation {
+    /// Parse a request body of the form
+    /// `{"query": ..., "operationName": ..., "variables": ...}`.
+    fn parse(body: &[u8]) -> Option<Self> {
+        let mut body = match serde_json::from_slice(body).ok()? {
+            Value::Object(body) => body,
+            _ => return None,
+        };
+
+        let query = match body.remove("query")? {
+            Value::String(query) => tokenize(&query),
+            _ => return None,
+        };
+
+        let name = match body.remove("operationName") {
+            Some(Value::String(name)) => Some(name),
+            Some(Value::Null) | None => None,
+            Some(_) => return None,
+        };
+
+        // Omitted and empty variables are equivalent.
+        let variables = match body.remove("variables") {
+            Some(Value::Null) | None => Value::Object(Map::new()),
+            Some(variables) => variables,
+        };
+
+        Some(Self { query, name, variables })
+    }
+}
+
+/// Split a GraphQL document into its significant tokens.
+///
+/// Whitespace, commas, and comments are insignificant; strings are kept
+/// intact, including their quotes.
+fn tokenize(query: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut chars = query.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        match c {
+            c if c.is_whitespace() || c == ',' || c == '\u{feff}' => {},
+            '#' => {
+                while chars.next_if(|&(_, c)| c != '\n' && c != '\r')
+                    .is_some() {}
+            },
+            '"' => {
+                let rest = &query[start..];
+                let len = if rest.starts_with("\"\"\"") {
+                    block_string_len(rest)
+                } else {
+                    string_len(rest)
+                };
+
+                tokens.push(rest[..len].to_owned());
+
+                while chars.next_if(|&(i, _)| i < start + len).is_some() {}
+            },
+            '.' if query[start..].starts_with("...") => {
+                tokens.push("...".into());
+                chars.next();
+                chars.next();
+            },
+            c if is_name_char(c) || c == '-' => {
+                let mut end = start + c.len_utf8();
+
+                while let Some((i, c)) = chars.next_if(|&(_, c)| {
+                    is_name_char(c) || c == '.' || c == '+' || c == '-'
+                }) {
+                    end = i + c.len_utf8();
+                }
+
+                tokens.push(query[start..end].to_owned());
+            },
+            c => tokens.push(c.to_string()),
+        }
+    }
+
+    tokens
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// The length of the string at the start of `s`, including its quotes.
+fn string_len(s: &str) -> usize {
+    let mut escaped = false;
+
+    for (i, c) in s.char_indices().skip(1) {
+        match c {
+            '\\' if ! escaped => escaped = true,
+            '"' if ! escaped => return i + 1,
+            '\n' | '\r' => return i,
+            _ => escaped = false,
+        }
+    }
+
+    s.len()
+}
+
+/// The length of the block string at the start of `s`, including its quotes.
+fn block_string_len(s: &str) -> usize {
+    let mut rest = &s[3..];
+
+    while let Some(pos) = rest.find("\"\"\"") {
+        let escaped = rest[..pos].ends_with('\\');
+        rest = &rest[pos + 3..];
+
+        if ! escaped {
+            return s.len() - rest.len();
+        }
+    }
+
+    s.len()
+}
@@ -0,0 +1,1080 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! How requests are matched against the recorded ones: the middleware's
+//! matching options, their builders, and the header and body normalizers.
+
+use std::{collections::HashMap, sync::Arc};
+
+use surf::http::Method;
+
+use crate::{Body, BodyMatcher, RequestModifier, VcrMiddleware, VcrRequest};
+
+
+/// How a middleware matches requests against the recorded ones.
+#[derive(Clone)]
+pub(crate) struct MatchOptions {
+    pub(crate) match_on_effective_url: bool,
+    pub(crate) ignore_trailing_slash: bool,
+    pub(crate) body_match_prefix: Option<usize>,
+    pub(crate) whitespace_insensitive_body: bool,
+    pub(crate) json_number_tolerance: Option<f64>,
+    pub(crate) cookie_aware_matching: bool,
+    pub(crate) media_type_aware_matching: bool,
+    pub(crate) presence_only_headers: Vec<String>,
+    pub(crate) header_subset_matching: bool,
+    pub(crate) xml_bodies: bool,
+    pub(crate) graphql_bodies: bool,
+    pub(crate) body_matcher: Option<Arc<BodyMatcher>>,
+    pub(crate) request_normalizer: Option<Arc<RequestModifier>>,
+    /// The methods whose requests are matched without their bodies.
+    pub(crate) bodyless_methods: Vec<Method>,
+}
+
+impl Default for MatchOptions {
+    fn default() -> Self {
+        Self {
+            match_on_effective_url: false,
+            ignore_trailing_slash: false,
+            body_match_prefix: None,
+            whitespace_insensitive_body: false,
+            json_number_tolerance: None,
+            cookie_aware_matching: false,
+            media_type_aware_matching: false,
+            presence_only_headers: vec![],
+            header_subset_matching: false,
+            xml_bodies: false,
+            graphql_bodies: false,
+            body_matcher: None,
+            request_normalizer: None,
+            bodyless_methods: vec![Method::Get, Method::Head, Method::Delete],
+        }
+    }
+}
+
+/// Combine the `Cookie` headers into a single, sorted set of name=value pairs
+/// so the order in which cookies are sent doesn't matter.
+pub(crate) fn normalize_cookies(headers: &HashMap<String, Vec<String>>)
+-> HashMap<String, Vec<String>> {
+    headers.iter()
+        .map(|(name, values)| {
+            if ! name.eq_ignore_ascii_case("cookie") {
+                return (name.clone(), values.clone());
+            }
+
+            let mut pairs = values.iter()
+                .flat_map(|v| v.split(';'))
+                .map(str::trim)
+                .filter(|pair| ! pair.is_empty())
+                .collect::<Vec<_>>();
+
+            pairs.sort_unstable();
+            pairs.dedup();
+
+            (name.clone(), vec![pairs.join("; ")])
+        })
+        .collect()
+}
+
+/// Rewrite the `Accept` and `Content-Type` headers in a canonical form so the
+/// formatting and order of their media types' parameters don't matter.
+pub(crate) fn normalize_media_types(headers: &HashMap<String, Vec<String>>)
+-> HashMap<String, Vec<String>> {
+    headers.iter()
+        .map(|(name, values)| {
+            if ! name.eq_ignore_ascii_case("accept")
+                && ! name.eq_ignore_ascii_case("content-type")
+            {
+                return (name.clone(), values.clone());
+            }
+
+            let values = values.iter()
+                .map(|v| {
+                    v.split(',')
+                        .map(normalize_media_type)
+                        .filter(|range| ! range.is_empty())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                })
+                .collect();
+
+            (name.clone(), values)
+        })
+        .collect()
+}
+
+fn normalize_media_type(media_type: &str) -> String {
+    let mut parts = media_type.split(';').map(str::trim);
+    let essence = parts.next().unwrap_or("").to_ascii_lowercase();
+
+    let mut params = parts
+        .filter(|param| ! param.is_empty())
+        .map(|param| {
+            let (name, value) = match param.find('=') {
+                Some(i) => (&param[..i], &param[i+1..]),
+                None => (param, ""),
+            };
+            let name = name.trim().to_ascii_lowercase();
+            let value = value.trim().trim_matches('"');
+
+            // Charset names are case-insensitive.
+            if name == "charset" {
+                format!("{}={}", name, value.to_ascii_lowercase())
+            } else {
+                format!("{}={}", name, value)
+            }
+        })
+        .collect::<Vec<_>>();
+
+    params.sort_unstable();
+    params.insert(0, essence);
+    params.join(";")
+}
+
+/// Headers whose values are lists of elements or parameters separated by `,`
+/// or `;`, around which whitespace is insignificant.
+const STRUCTURED_HEADERS: &[&str] = &[
+    "accept",
+    "accept-charset",
+    "accept-encoding",
+    "accept-language",
+    "cache-control",
+    "content-disposition",
+    "content-encoding",
+    "content-type",
+    "cookie",
+    "te",
+    "transfer-encoding",
+    "vary",
+];
+
+/// Remove insignificant whitespace from the values of structured headers, so
+/// (for example) `text/plain; charset=utf-8` matches
+/// `text/plain;charset=utf-8`; other headers are left as they are.
+pub(crate) fn normalize_header_whitespace(
+    mut headers: HashMap<String, Vec<String>>
+) -> HashMap<String, Vec<String>> {
+    for (name, values) in headers.iter_mut() {
+        let structured = STRUCTURED_HEADERS.iter()
+            .any(|h| name.eq_ignore_ascii_case(h));
+
+        if structured {
+            for value in values {
+                *value = normalize_structured_value(value);
+            }
+        }
+    }
+
+    headers
+}
+
+/// Trim each element of a structured header value, and collapse the runs of
+/// whitespace within them to a single space; quoted strings are left as they
+/// are.
+fn normalize_structured_value(value: &str) -> String {
+    let is_separator = |c: char| c == ',' || c == ';';
+
+    let mut out = String::with_capacity(value.len());
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut pending_space = false;
+
+    for c in value.chars() {
+        if in_string {
+            out.push(c);
+
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+        } else if c.is_whitespace() {
+            pending_space = true;
+        } else {
+            if pending_space && ! is_separator(c)
+                && ! out.is_empty() && ! out.ends_with(is_separator)
+            {
+                out.push(' ');
+            }
+            pending_space = false;
+
+            in_string = c == '"';
+            out.push(c);
+        }
+    }
+
+    out
+}
+
+/// Remove insignificant whitespace outside of quoted strings.
+///
+/// Whitespace between two word characters is collapsed to a single space; all
+/// other whitespace is removed, so `{ "a": [1, 2] }` and `{"a":[1,2]}` are
+/// equivalent.
+pub(crate) fn collapse_whitespace(text: &str) -> String {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+
+    let mut out = String::with_capacity(text.len());
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut pending_space = false;
+
+    for c in text.chars() {
+        if in_string {
+            out.push(c);
+
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+        } else if c.is_whitespace() {
+            pending_space = true;
+        } else {
+            if pending_space && out.ends_with(is_word) && is_word(c) {
+                out.push(' ');
+            }
+            pending_space = false;
+
+            in_string = c == '"';
+            out.push(c);
+        }
+    }
+
+    out
+}
+
+impl VcrMiddleware {
+    /// Match requests against the final URL of each recorded request (after
+    /// any redirects were followed) rather than the URL originally requested.
+    ///
+    /// Surf doesn't report the final URL of a request, so it's only recorded
+    /// if something after this middleware (another middleware, or the HTTP
+    /// client) inserts it into the response's extensions as a [Url]; see
+    /// [VcrRequest::effective_url]. Recorded requests without an effective URL
+    /// are matched against their original URL.
+    ///
+    /// [Url]: surf::Url
+    pub fn with_match_on_effective_url(mut self, effective: bool) -> Self {
+        self.core.match_options.match_on_effective_url = effective;
+        self
+    }
+
+    /// Set the methods whose request bodies are not compared while replaying.
+    ///
+    /// By default, the bodies of `GET`, `HEAD`, and `DELETE` requests are
+    /// ignored, since they conventionally have none and clients may send an
+    /// empty body or no body at all. Pass an empty slice to compare the bodies
+    /// of every request.
+    pub fn with_bodyless_methods(mut self, methods: &[Method]) -> Self {
+        self.core.match_options.bodyless_methods = methods.to_vec();
+        self
+    }
+
+    /// Compare only the first `len` bytes of request bodies while replaying.
+    ///
+    /// Full bodies are still recorded.
+    pub fn with_body_match_prefix(mut self, len: usize) -> Self {
+        self.core.match_options.body_match_prefix = Some(len);
+        self
+    }
+
+    /// Set whether to ignore a trailing slash on URL paths while replaying.
+    ///
+    /// When enabled, `/widgets` and `/widgets/` match each other. Recorded URLs
+    /// are stored as they were sent.
+    pub fn with_ignore_trailing_slash(mut self, ignore: bool) -> Self {
+        self.core.match_options.ignore_trailing_slash = ignore;
+        self
+    }
+
+    /// Ignore insignificant whitespace in string bodies while replaying.
+    ///
+    /// Runs of whitespace outside of quoted strings are collapsed before
+    /// comparing, so (for example) pretty-printed and minified JSON or XML
+    /// bodies match. Byte bodies are compared as-is.
+    pub fn with_whitespace_insensitive_body(mut self, insensitive: bool)
+    -> Self {
+        self.core.match_options.whitespace_insensitive_body = insensitive;
+        self
+    }
+
+    /// Allow numbers in JSON bodies to differ by up to `epsilon` while
+    /// replaying.
+    ///
+    /// This applies only when both the recorded and the sent bodies are valid
+    /// JSON; other bodies are compared exactly.
+    pub fn with_json_number_tolerance(mut self, epsilon: f64) -> Self {
+        self.core.match_options.json_number_tolerance = Some(epsilon);
+        self
+    }
+
+    /// Compare `Cookie` headers as sets of cookies while replaying.
+    ///
+    /// When enabled, the cookies in a request's `Cookie` headers match the
+    /// recorded cookies regardless of the order in which they're sent.
+    pub fn with_cookie_aware_matching(mut self, cookie_aware: bool) -> Self {
+        self.core.match_options.cookie_aware_matching = cookie_aware;
+        self
+    }
+
+    /// Compare `Accept` and `Content-Type` headers as media types while
+    /// replaying.
+    ///
+    /// When enabled, each media type's type, subtype, and parameter names are
+    /// compared without regard to case, and its parameters without regard to
+    /// order or quoting; `text/plain; Charset="UTF-8"; format=flowed` matches
+    /// `text/plain;format=flowed;charset=utf-8`. The media ranges of an
+    /// `Accept` header must still be listed in the same order.
+    pub fn with_media_type_aware_matching(mut self, media_types: bool)
+    -> Self {
+        self.core.match_options.media_type_aware_matching = media_types;
+        self
+    }
+
+    /// Require the given headers to be sent while replaying, without comparing
+    /// their values.
+    ///
+    /// A request matches only if it has a non-empty value for each of these
+    /// headers, whatever their recorded values; this catches a missing
+    /// `Authorization` header while tolerating rotating tokens.
+    pub fn with_presence_only_headers(mut self, names: &[&str]) -> Self {
+        self.core.match_options.presence_only_headers = names.iter()
+            .map(|name| name.to_ascii_lowercase())
+            .collect();
+        self
+    }
+
+    /// Match requests that have at least the recorded headers while replaying.
+    ///
+    /// When enabled, a request matches if it has every recorded header with
+    /// the recorded values, even if it also has headers the recording lacks;
+    /// clients can then add headers without invalidating their cassettes.
+    /// Every header is still recorded.
+    pub fn with_header_subset_matching(mut self, subset: bool) -> Self {
+        self.core.match_options.header_subset_matching = subset;
+        self
+    }
+
+    /// Compare XML request bodies by their content while replaying.
+    ///
+    /// The order of attributes, whitespace between elements, comments, and
+    /// namespace prefixes are ignored. Bodies that aren't both valid XML are
+    /// compared as usual.
+    #[cfg(feature = "xml")]
+    pub fn with_xml_body_matching(mut self, xml: bool) -> Self {
+        self.core.match_options.xml_bodies = xml;
+        self
+    }
+
+    /// Compare GraphQL request bodies by their operation while replaying.
+    ///
+    /// The `query`, `operationName`, and `variables` of JSON request bodies are
+    /// compared; formatting and comments in the query and the order of the
+    /// variables are ignored, so different operations sent to the same
+    /// endpoint are distinguished. Bodies that aren't both GraphQL requests
+    /// are compared as usual.
+    #[cfg(feature = "graphql")]
+    pub fn with_graphql_body_matching(mut self, graphql: bool) -> Self {
+        self.core.match_options.graphql_bodies = graphql;
+        self
+    }
+
+    /// Compare request bodies with `matcher` while replaying.
+    ///
+    /// The matcher is called with the recorded body and the body of the new
+    /// request, and replaces every other body comparison; the method, URL, and
+    /// headers are still matched as usual.
+    pub fn with_body_matcher<F>(mut self, matcher: F) -> Self
+        where F: Fn(&Body, &Body) -> bool + Send + Sync + 'static
+    {
+        self.core.match_options.body_matcher = Some(Arc::new(matcher));
+        self
+    }
+
+    /// Normalize requests with `normalizer` before matching them while
+    /// replaying.
+    ///
+    /// The normalizer is applied to copies of both the recorded request and
+    /// the new request, which are then compared as usual; unlike
+    /// [VcrMiddleware::with_modify_request], it doesn't change what is
+    /// recorded.
+    pub fn with_request_normalizer<F>(mut self, normalizer: F) -> Self
+        where F: Fn(&mut VcrRequest) + Send + Sync + 'static
+    {
+        self.core.match_options.request_normalizer = Some(Arc::new(normalizer));
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[cfg(feature = "yaml")]
+    #[async_std::test]
+    async fn match_on_effective_url() -> Result<(), VcrError> {
+        let path = "test-sessions/redirect.yml";
+
+        let client = surf::Client::new()
+            .with(VcrMiddleware::new(VcrMode::Replay, path).await?);
+
+        let req = surf::get("https://example.com/new-location").build();
+        assert!(client.send(req).await.is_err());
+
+        let req = surf::get("https://example.com/old-location").build();
+        assert!(client.send(req).await.is_ok());
+
+        let client = surf::Client::new()
+            .with(
+                VcrMiddleware::new(VcrMode::Replay, path).await?
+                    .with_match_on_effective_url(true)
+            );
+
+        let req = surf::get("https://example.com/new-location").build();
+        let mut res = client.send(req).await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "Redirected Response");
+
+        Ok(())
+    }
+
+    #[cfg(feature = "yaml")]
+    #[async_std::test]
+    async fn match_body_prefix() -> Result<(), VcrError> {
+        let path = "test-sessions/body-prefix.yml";
+
+        let req = || surf::put("https://example.com/upload")
+            .body_bytes(b"MAGIC\x00\x01 a different payload")
+            .build();
+
+        let client = surf::Client::new()
+            .with(VcrMiddleware::new(VcrMode::Replay, path).await?);
+
+        assert!(client.send(req()).await.is_err());
+
+        let client = surf::Client::new()
+            .with(
+                VcrMiddleware::new(VcrMode::Replay, path).await?
+                    .with_body_match_prefix(7)
+            );
+
+        let mut res = client.send(req()).await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "Uploaded");
+
+        Ok(())
+    }
+
+    #[test]
+    fn collapse_insignificant_whitespace() {
+        assert_eq!(
+            collapse_whitespace(
+                "{\n  \"a b\": [1, 2],\n  \"c\": \"\\\"  \"\n}"
+            ),
+            r#"{"a b":[1,2],"c":"\"  "}"#
+        );
+        assert_eq!(
+            collapse_whitespace("<a  x=\"1\">\n  <b>some   text</b>\n</a>"),
+            r#"<a x="1"><b>some text</b></a>"#
+        );
+    }
+
+    #[cfg(feature = "yaml")]
+    #[async_std::test]
+    async fn match_bodies_ignoring_whitespace() -> Result<(), VcrError> {
+        let path = "test-sessions/whitespace.yml";
+
+        let req = || surf::post("https://example.com/widgets")
+            .header("Content-Type", "application/json")
+            .body_string(r#"{"id":5,"tags":["a","b"]}"#.into())
+            .build();
+
+        let client = surf::Client::new()
+            .with(VcrMiddleware::new(VcrMode::Replay, path).await?);
+
+        assert!(client.send(req()).await.is_err());
+
+        let client = surf::Client::new()
+            .with(
+                VcrMiddleware::new(VcrMode::Replay, path).await?
+                    .with_whitespace_insensitive_body(true)
+            );
+
+        assert!(client.send(req()).await.is_ok());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "yaml")]
+    #[async_std::test]
+    async fn match_json_numbers_within_tolerance() -> Result<(), VcrError> {
+        let path = "test-sessions/measurements.yml";
+
+        let req = |body: &str| surf::post("https://example.com/measurements")
+            .header("Content-Type", "application/json")
+            .body_string(body.into())
+            .build();
+
+        let close = r#"{"temperature":21.5000001,"readings":[0.1,0.2000001],
+            "unit":"C"}"#;
+        let far = r#"{"temperature":21.6,"readings":[0.1,0.2],"unit":"C"}"#;
+
+        let client = surf::Client::new()
+            .with(VcrMiddleware::new(VcrMode::Replay, path).await?);
+
+        assert!(client.send(req(close)).await.is_err());
+
+        let client = surf::Client::new()
+            .with(
+                VcrMiddleware::new(VcrMode::Replay, path).await?
+                    .with_json_number_tolerance(1e-6)
+            );
+
+        assert!(client.send(req(close)).await.is_ok());
+        assert!(client.send(req(far)).await.is_err());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "yaml")]
+    #[async_std::test]
+    async fn match_cookies_in_any_order() -> Result<(), VcrError> {
+        let path = "test-sessions/cookies.yml";
+
+        let req = |cookie: &str| surf::get("https://example.com/account")
+            .header("Cookie", cookie)
+            .build();
+
+        let client = surf::Client::new()
+            .with(VcrMiddleware::new(VcrMode::Replay, path).await?);
+
+        assert!(client.send(req("theme=dark; session=abc123")).await.is_err());
+
+        let client = surf::Client::new()
+            .with(
+                VcrMiddleware::new(VcrMode::Replay, path).await?
+                    .with_cookie_aware_matching(true)
+            );
+
+        assert!(client.send(req("theme=dark; session=abc123")).await.is_ok());
+        assert!(client.send(req("theme=dark;session=abc123")).await.is_ok());
+        assert!(client.send(req("theme=light; session=abc123")).await.is_err());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "yaml")]
+    #[async_std::test]
+    async fn match_media_types_with_reordered_parameters()
+    -> Result<(), VcrError> {
+        let path = "test-sessions/media-types.yml";
+
+        let req = |accept: &str, content_type: &str|
+            surf::get("https://example.com/report")
+                .header("Accept", accept)
+                .header("Content-Type", content_type)
+                .build();
+
+        let client = surf::Client::new()
+            .with(VcrMiddleware::new(VcrMode::Replay, path).await?);
+
+        assert!(client.send(req(
+            "text/html;level=1;q=0.8, application/json",
+            "application/json; version=2; charset=UTF-8"
+        )).await.is_err());
+
+        let client = surf::Client::new()
+            .with(
+                VcrMiddleware::new(VcrMode::Replay, path).await?
+                    .with_media_type_aware_matching(true)
+            );
+
+        assert!(client.send(req(
+            "text/html; q=0.8; level=1, application/json",
+            "application/json; charset=utf-8; version=2"
+        )).await.is_ok());
+        assert!(client.send(req(
+            "Text/HTML;level=1;q=0.8,application/json",
+            "application/json; version=\"2\"; Charset=UTF-8"
+        )).await.is_ok());
+        assert!(client.send(req(
+            "application/json, text/html; q=0.8; level=1",
+            "application/json; charset=utf-8; version=2"
+        )).await.is_err());
+        assert!(client.send(req(
+            "text/html; q=0.8; level=1, application/json",
+            "application/json; charset=utf-8; version=3"
+        )).await.is_err());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "yaml")]
+    #[async_std::test]
+    async fn require_presence_only_headers() -> Result<(), VcrError> {
+        let path = "test-sessions/simple.yml";
+
+        let req = |key: Option<&str>| {
+            let req = surf::get("https://example.com")
+                .header("X-some-header", "another hello")
+                .header("Content-Type", "application/octet-stream");
+
+            match key {
+                Some(key) => req.header("session-key", key).build(),
+                None => req.build(),
+            }
+        };
+
+        let client = surf::Client::new()
+            .with(VcrMiddleware::new(VcrMode::Replay, path).await?);
+
+        assert!(client.send(req(Some("rotated"))).await.is_err());
+
+        let client = surf::Client::new()
+            .with(
+                VcrMiddleware::new(VcrMode::Replay, path).await?
+                    .with_presence_only_headers(&["Session-Key"])
+            );
+
+        let mut res = client.send(req(Some("rotated"))).await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "And Another Response");
+        assert!(client.send(req(Some(""))).await.is_err());
+        assert!(client.send(req(None)).await.is_err());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "yaml")]
+    #[async_std::test]
+    async fn ignore_whitespace_in_header_values() -> Result<(), VcrError> {
+        let client = surf::Client::new()
+            .with(
+                VcrMiddleware::new(VcrMode::Replay, "test-sessions/cookies.yml")
+                    .await?
+            );
+
+        let req = |cookie: &str| surf::get("https://example.com/account")
+            .header("Cookie", cookie)
+            .build();
+
+        assert!(client.send(req("session=abc123 ;  theme=dark")).await.is_ok());
+        assert!(client.send(req("session=abc 123; theme=dark")).await.is_err());
+
+        let path = "test-sessions/record-header-whitespace.yml";
+        let mut cassette = Cassette::new();
+        cassette.push(
+            VcrRequest {
+                method: Method::Post,
+                url: Url::parse("https://example.com/notes").unwrap(),
+                effective_url: None,
+                label: None,
+                id: None,
+                headers: IntoIterator::into_iter([
+                    ("content-type", "text/plain; charset=\"utf - 8\""),
+                    ("x-note", "two  spaces"),
+                ]).map(|(k, v)| (k.to_owned(), vec![v.to_owned()])).collect(),
+                body: Body::Str("note".into()),
+                body_hash: None,
+            },
+            VcrResponse {
+                status: StatusCode::Ok,
+                version: None,
+                headers: HashMap::new(),
+                body: Body::Str("Noted".into()),
+                body_hash: None,
+            }
+        );
+        cassette.save(path).await?;
+
+        let client = surf::Client::new()
+            .with(VcrMiddleware::new(VcrMode::Replay, path).await?);
+
+        let req = |content_type: &str, note: &str| {
+            surf::post("https://example.com/notes")
+                .header("Content-Type", content_type)
+                .header("X-Note", note)
+                .body("note")
+                .build()
+        };
+
+        let sent = req(" text/plain ;charset=\"utf - 8\"", "two  spaces");
+        assert!(client.send(sent).await.is_ok());
+
+        // Whitespace within quoted strings and unstructured headers counts.
+        let sent = req("text/plain; charset=\"utf-8\"", "two  spaces");
+        assert!(client.send(sent).await.is_err());
+        let sent = req("text/plain; charset=\"utf - 8\"", "two spaces");
+        assert!(client.send(sent).await.is_err());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "yaml")]
+    #[async_std::test]
+    async fn override_matching_per_request() -> Result<(), VcrError> {
+        let client = surf::Client::new()
+            .with(VcrMiddleware::new(
+                VcrMode::Replay,
+                "test-sessions/whitespace.yml"
+            ).await?);
+
+        let req = || surf::post("https://example.com/widgets")
+            .header("Content-Type", "application/json")
+            .body_string("{}".into())
+            .build();
+
+        assert!(client.send(req()).await.is_err());
+
+        let mut relaxed = req();
+        relaxed.set_ext(MatchOverrides {
+            ignore_body: true,
+            ..Default::default()
+        });
+
+        assert!(client.send(relaxed).await.is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn ignore_case_of_percent_encoding() {
+        let rules = MatchRules::default();
+        let url = |s| Url::parse(s).unwrap();
+
+        assert!(rules.urls_match(
+            &url("https://example.com/files/a%2Fb?q=%e2%9c%93"),
+            &url("https://example.com/files/a%2fb?q=%E2%9C%93")
+        ));
+        assert!(! rules.urls_match(
+            &url("https://example.com/files/a%2Fb"),
+            &url("https://example.com/files/a%2Fc")
+        ));
+        assert_eq!(
+            normalize_percent_encoding(&url("https://example.com/%af%zz"))
+                .as_str(),
+            "https://example.com/%AF%zz"
+        );
+    }
+
+    #[cfg(feature = "yaml")]
+    #[async_std::test]
+    async fn match_bodies_regardless_of_encoding() -> Result<(), VcrError> {
+        assert_eq!(Body::Str("hi".into()), Body::Bytes(b"hi".to_vec()));
+        assert_ne!(Body::Str("hi".into()), Body::Bytes(b"ho".to_vec()));
+        assert_ne!(
+            Body::Str(String::new()),
+            Body::File { file: PathBuf::new() }
+        );
+
+        let vcr = VcrMiddleware::new(
+            VcrMode::Replay,
+            "test-sessions/variant.yml"
+        ).await?
+            .with_modify_request(|req| req.body.make_binary());
+        let client = surf::Client::new().with(vcr);
+
+        let req = surf::get("https://example.com/widgets").build();
+        let mut res = client.send(req).await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "base widgets");
+
+        Ok(())
+    }
+
+    #[cfg(feature = "yaml")]
+    #[async_std::test]
+    async fn ignore_bodies_of_bodyless_methods() -> Result<(), VcrError> {
+        let req = || {
+            let mut req = surf::get("https://example.com/widgets")
+                .body("unexpected")
+                .build();
+            req.remove_header("content-type");
+            req
+        };
+
+        let client = surf::Client::new().with(VcrMiddleware::new(
+            VcrMode::Replay,
+            "test-sessions/variant.yml"
+        ).await?);
+        assert!(client.send(req()).await.is_ok());
+
+        let client = surf::Client::new().with(VcrMiddleware::new(
+            VcrMode::Replay,
+            "test-sessions/variant.yml"
+        ).await?.with_bodyless_methods(&[]));
+        assert!(client.send(req()).await.is_err());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "yaml")]
+    #[async_std::test]
+    async fn ignore_trailing_slashes() -> Result<(), VcrError> {
+        let vcr = || VcrMiddleware::new(
+            VcrMode::Replay,
+            "test-sessions/variant.yml"
+        );
+
+        let client = surf::Client::new().with(vcr().await?);
+        assert!(client.get("https://example.com/widgets/").await.is_err());
+
+        let client = surf::Client::new()
+            .with(vcr().await?.with_ignore_trailing_slash(true));
+
+        let mut res = client.get("https://example.com/widgets/").await
+            .unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "base widgets");
+        assert!(client.get("https://example.com/widgets?a=1").await.is_err());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "yaml")]
+    #[async_std::test]
+    async fn normalize_requests_before_matching() -> Result<(), VcrError> {
+        let path = "test-sessions/record-normalized.yml";
+        let _ = async_std::fs::remove_file(path).await;
+
+        let client = surf::Client::new()
+            .with(VcrMiddleware::new(VcrMode::Record, path).await?)
+            .with(VcrMiddleware::new(
+                VcrMode::Replay,
+                "test-sessions/variant.yml"
+            ).await?.with_request_normalizer(|req| {
+                req.headers.remove("x-timestamp");
+            }));
+
+        let req = surf::get("https://example.com/widgets")
+            .header("X-Timestamp", "1000");
+        client.send(req).await.unwrap();
+
+        // The normalizer doesn't change the recording.
+        let text = async_std::fs::read_to_string(path).await?;
+        assert!(text.contains("\"1000\""));
+
+        let normalize = |req: &mut VcrRequest| {
+            req.headers.entry("x-timestamp".into())
+                .and_modify(|val| *val = vec!["(time)".into()]);
+        };
+
+        let client = surf::Client::new().with(
+            VcrMiddleware::new(VcrMode::Replay, path).await?
+                .with_request_normalizer(normalize)
+        );
+        let req = surf::get("https://example.com/widgets")
+            .header("X-Timestamp", "2000");
+        let mut res = client.send(req).await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "base widgets");
+
+        let client = surf::Client::new()
+            .with(VcrMiddleware::new(VcrMode::Replay, path).await?);
+        let req = surf::get("https://example.com/widgets")
+            .header("X-Timestamp", "2000");
+        assert!(client.send(req).await.is_err());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "yaml")]
+    #[async_std::test]
+    async fn match_header_subsets() -> Result<(), VcrError> {
+        let client = surf::Client::new().with(
+            VcrMiddleware::new(VcrMode::Replay, "test-sessions/simple.yml")
+                .await?
+                .with_header_subset_matching(true)
+        );
+
+        let req = |extra: bool| {
+            let mut req = surf::get("https://example.com")
+                .header("X-some-header", "another hello")
+                .header("Content-Type", "application/octet-stream")
+                .header("session-key", "00112233445566778899AABBCCDDEEFF")
+                .build();
+
+            if extra {
+                req.insert_header("X-Added-Later", "1");
+            }
+            req
+        };
+
+        let mut res = client.send(req(true)).await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "And Another Response");
+
+        let mut missing = req(false);
+        missing.remove_header("session-key");
+        assert!(client.send(missing).await.is_err());
+
+        let client = surf::Client::new().with(
+            VcrMiddleware::new(VcrMode::Replay, "test-sessions/simple.yml")
+                .await?
+        );
+        assert!(client.send(req(true)).await.is_err());
+        client.send(req(false)).await.unwrap();
+
+        Ok(())
+    }
+
+    #[cfg(feature = "yaml")]
+    #[async_std::test]
+    async fn match_headers_named_by_vary() -> Result<(), VcrError> {
+        let client = surf::Client::new()
+            .with(VcrMiddleware::new(
+                VcrMode::Replay,
+                "test-sessions/vary.yml"
+            ).await?);
+
+        let req = surf::get("https://example.com/report")
+            .header("Accept", "text/csv")
+            .header("X-request-id", "1234")
+            .build();
+        let mut res = client.send(req).await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "a,b");
+
+        let req = surf::get("https://example.com/report")
+            .header("Accept", "application/json")
+            .build();
+        let mut res = client.send(req).await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "[\"a\", \"b\"]");
+
+        // Without a Vary header, all headers are compared.
+        let req = surf::get("https://example.com/unvaried")
+            .header("X-request-id", "1234")
+            .build();
+        assert!(client.send(req).await.is_err());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "xml")]
+    #[async_std::test]
+    async fn match_xml_bodies() -> Result<(), VcrError> {
+        let vcr = VcrMiddleware::new(VcrMode::Replay, "test-sessions/xml.yml")
+            .await?
+            .with_xml_body_matching(true);
+        let client = surf::Client::new().with(vcr);
+
+        let req = surf::post("https://example.com/orders")
+            .body("<order  xmlns='urn:shop' id='7' priority=\"high\">\n  \
+                <!-- rush --><item qty='2'>widget</item>\n</order>")
+            .build();
+        let mut res = client.send(req).await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "Accepted");
+
+        let req = surf::post("https://example.com/orders")
+            .body("<order id=\"7\" priority=\"low\"></order>")
+            .build();
+        assert!(client.send(req).await.is_err());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "graphql")]
+    #[async_std::test]
+    async fn match_graphql_bodies() -> Result<(), VcrError> {
+        use serde_json::json;
+
+        let vcr = VcrMiddleware::new(
+            VcrMode::Replay,
+            "test-sessions/graphql.yml"
+        ).await?.with_graphql_body_matching(true);
+        let client = surf::Client::new().with(vcr);
+
+        let req = surf::post("https://example.com/graphql")
+            .body(json!({
+                "operationName": "Widget",
+                "variables": { "locale": "en", "id": "7" },
+                "query": "query Widget($id: ID!) {\n  # By ID\n  \
+                    widget(id: $id) {\n    name\n  }\n}",
+            }))
+            .build();
+        let mut res = client.send(req).await.unwrap();
+        assert!(res.body_string().await.unwrap().contains("Sprocket"));
+
+        // Whitespace and commas within strings are significant.
+        let req = surf::post("https://example.com/graphql")
+            .body(json!({
+                "query": "query Gadgets {\n  \
+                    gadgets(filter: \"shiny, new\") { name }\n}",
+                "operationName": "Gadgets",
+                "variables": null,
+            }))
+            .build();
+        let mut res = client.send(req).await.unwrap();
+        assert!(res.body_string().await.unwrap().contains("gadgets"));
+
+        let req = surf::post("https://example.com/graphql")
+            .body(json!({
+                "query": "query Gadgets { gadgets(filter: \"shiny new\") \
+                    { name } }",
+                "operationName": "Gadgets",
+            }))
+            .build();
+        assert!(client.send(req).await.is_err());
+
+        let req = surf::post("https://example.com/graphql")
+            .body(json!({
+                "query": "query Widget($id: ID!) { widget(id: $id) { name } }",
+                "operationName": "Widget",
+                "variables": { "id": "8", "locale": "en" },
+            }))
+            .build();
+        assert!(client.send(req).await.is_err());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "yaml")]
+    #[async_std::test]
+    async fn match_bodies_with_predicate() -> Result<(), VcrError> {
+        let vcr = VcrMiddleware::new(VcrMode::Replay, "test-sessions/xml.yml")
+            .await?
+            .with_body_matcher(|recorded, body| {
+                recorded.as_bytes().len() == body.as_bytes().len()
+            });
+        let client = surf::Client::new().with(vcr);
+
+        // Same length as the recorded body.
+        let req = surf::post("https://example.com/orders")
+            .body("x".repeat(114))
+            .build();
+        let mut res = client.send(req).await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "Accepted");
+
+        let req = surf::post("https://example.com/orders")
+            .body("x".repeat(20))
+            .build();
+        assert!(client.send(req).await.is_err());
+
+        // The URL is still compared.
+        let req = surf::post("https://example.com/carts")
+            .body("x".repeat(114))
+            .build();
+        assert!(client.send(req).await.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn hash_normalized_headers() {
+        let headers = |pairs: &[(&str, &str)]| pairs.iter()
+            .map(|(k, v)| (k.to_string(), vec![v.to_string()]))
+            .collect::<HashMap<_, _>>();
+
+        assert_eq!(
+            headers_hash(&headers(&[("accept", "a/b; x=1"), ("b", "2")])),
+            headers_hash(&headers(&[("b", "2"), ("accept", "a/b;x=1")]))
+        );
+        assert_ne!(
+            headers_hash(&headers(&[("a", "text/plain; x=1")])),
+            headers_hash(&headers(&[("a", "text/plain;x=1")]))
+        );
+        assert_ne!(
+            headers_hash(&headers(&[("a", "1")])),
+            headers_hash(&headers(&[("a", "1"), ("b", "2")]))
+        );
+    }
+}
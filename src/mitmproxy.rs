@@ -0,0 +1,313 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Import of mitmproxy flow files.
+//!
+//! Flow files are a sequence of tnetstrings
+//! (<https://tnetstrings.info/>), each describing a single flow.
+
+use std::{collections::HashMap, convert::TryFrom, path::Path};
+
+use async_std::fs;
+
+use surf::{
+    http::{Method, Version},
+    StatusCode,
+    Url,
+};
+
+use crate::{Body, Cassette, VcrError, VcrRequest, VcrResponse};
+
+
+impl Cassette {
+    /// Import the HTTP flows saved by mitmproxy in the given file.
+    ///
+    /// Flows without a response (e.g., because the connection failed) and
+    /// non-HTTP flows are skipped.
+    pub async fn from_mitmproxy<P>(path: P) -> Result<Self, VcrError>
+        where P: AsRef<Path>,
+    {
+        let data = fs::read(path.as_ref()).await?;
+        let mut data = data.as_slice();
+        let mut cassette = Cassette::new();
+
+        while ! data.is_empty() {
+            let (flow, rest) = TNetString::parse(data)?;
+            data = rest;
+
+            if flow.get("type").and_then(TNetString::as_str) != Some("http") {
+                continue;
+            }
+
+            let response = match flow.get("response") {
+                Some(TNetString::Null) | None => continue,
+                Some(r) => r,
+            };
+            let request = flow.get("request")
+                .ok_or_else(|| import_error("flow is missing its request"))?;
+
+            cassette.push(
+                import_request(request)?,
+                import_response(response)?
+            );
+        }
+
+        Ok(cassette)
+    }
+}
+
+fn import_request(req: &TNetString) -> Result<VcrRequest, VcrError> {
+    let field = |name| req.get(name)
+        .and_then(TNetString::as_str)
+        .ok_or_else(|| import_error(format!("request is missing {}", name)));
+
+    let method = field("method")?.parse::<Method>()
+        .map_err(|e| import_error(e.to_string()))?;
+
+    let port = req.get("port").and_then(TNetString::as_int);
+    let url = format!(
+        "{}://{}{}{}",
+        field("scheme")?,
+        field("host")?,
+        port.map(|p| format!(":{}", p)).unwrap_or_default(),
+        field("path")?,
+    );
+
+    Ok(VcrRequest {
+        method,
+        url: Url::parse(&url).map_err(|e| import_error(e.to_string()))?,
+        effective_url: None,
+        label: None,
+        id: None,
+        headers: import_headers(req)?,
+        body: import_body(req),
+        body_hash: None,
+    })
+}
+
+fn import_response(resp: &TNetString) -> Result<VcrResponse, VcrError> {
+    let status = resp.get("status_code")
+        .and_then(TNetString::as_int)
+        .ok_or_else(|| import_error("response is missing status_code"))?;
+
+    let status = u16::try_from(status).ok()
+        .and_then(|s| StatusCode::try_from(s).ok())
+        .ok_or_else(|| import_error(format!("invalid status {}", status)))?;
+
+    let version = match resp.get("http_version").and_then(TNetString::as_str) {
+        Some("HTTP/0.9") => Some(Version::Http0_9),
+        Some("HTTP/1.0") => Some(Version::Http1_0),
+        Some("HTTP/1.1") => Some(Version::Http1_1),
+        Some("HTTP/2") | Some("HTTP/2.0") => Some(Version::Http2_0),
+        Some("HTTP/3") | Some("HTTP/3.0") => Some(Version::Http3_0),
+        _ => None,
+    };
+
+    Ok(VcrResponse {
+        status,
+        version,
+        headers: import_headers(resp)?,
+        body: import_body(resp),
+        body_hash: None,
+    })
+}
+
+fn import_headers(msg: &TNetString)
+-> Result<HashMap<String, Vec<String>>, VcrError> {
+    let mut headers: HashMap<String, Vec<String>> = HashMap::new();
+
+    let list = match msg.get("headers") {
+        Some(TNetString::List(l)) => l,
+        _ => return Ok(headers),
+    };
+
+    for header in list {
+        match header {
+            TNetString::List(pair) if pair.len() == 2 => {
+                let (name, value) = match (pair[0].as_str(), pair[1].as_str())
+                {
+                    (Some(n), Some(v)) => (n, v),
+                    _ => return Err(import_error("header is not valid UTF-8")),
+                };
+
+                // Surf's header names are lowercase, so we need ours to be too
+                // for the requests to match.
+                headers.entry(name.to_ascii_lowercase())
+                    .or_default()
+                    .push(value.to_owned());
+            },
+            _ => return Err(import_error("invalid header")),
+        }
+    }
+
+    Ok(headers)
+}
+
+fn import_body(msg: &TNetString) -> Body {
+    match msg.get("content") {
+        Some(TNetString::Bytes(b)) => Body::from(b.as_slice()),
+        Some(TNetString::Str(s)) => Body::Str(s.to_owned()),
+        _ => Body::Str(String::new()),
+    }
+}
+
+fn import_error<S: Into<String>>(msg: S) -> VcrError {
+    VcrError::Import(msg.into())
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum TNetString {
+    Bytes(Vec<u8>),
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Null,
+    List(Vec<TNetString>),
+    Dict(Vec<(TNetString, TNetString)>),
+}
+
+/// The deepest nesting of lists and dictionaries we parse; mitmproxy's flows
+/// are far shallower, so anything deeper is malformed (and would otherwise
+/// overflow the stack).
+const MAX_DEPTH: usize = 64;
+
+impl TNetString {
+    /// Parse a single tnetstring from the front of `data`, returning it and the
+    /// remaining data.
+    fn parse(data: &[u8]) -> Result<(Self, &[u8]), VcrError> {
+        Self::parse_nested(data, 0)
+    }
+
+    /// Parse a tnetstring nested within `depth` lists or dictionaries.
+    fn parse_nested(data: &[u8], depth: usize)
+    -> Result<(Self, &[u8]), VcrError> {
+        if depth > MAX_DEPTH {
+            return Err(import_error("tnetstring nested too deeply"));
+        }
+
+        let colon = data.iter().position(|b| *b == b':')
+            .ok_or_else(|| import_error("invalid tnetstring length"))?;
+
+        let len = std::str::from_utf8(&data[..colon]).ok()
+            .and_then(|len| len.parse::<usize>().ok())
+            .ok_or_else(|| import_error("invalid tnetstring length"))?;
+
+        let start = colon + 1;
+        let end = start.checked_add(len)
+            .filter(|end| *end < data.len())
+            .ok_or_else(|| import_error("truncated tnetstring"))?;
+
+        let payload = &data[start..end];
+        let text = || std::str::from_utf8(payload)
+            .map_err(|_| import_error("invalid tnetstring text"));
+
+        let val = match data[end] {
+            b',' => Self::Bytes(payload.to_vec()),
+            b';' => Self::Str(text()?.to_owned()),
+            b'#' => Self::Int(text()?.parse()
+                .map_err(|_| import_error("invalid tnetstring integer"))?),
+            b'^' => Self::Float(text()?.parse()
+                .map_err(|_| import_error("invalid tnetstring float"))?),
+            b'!' => Self::Bool(payload == b"true"),
+            b'~' => Self::Null,
+            b']' => {
+                let mut items = vec![];
+                let mut rest = payload;
+
+                while ! rest.is_empty() {
+                    let (item, r) = Self::parse_nested(rest, depth + 1)?;
+                    items.push(item);
+                    rest = r;
+                }
+
+                Self::List(items)
+            },
+            b'}' => {
+                let mut items = vec![];
+                let mut rest = payload;
+
+                while ! rest.is_empty() {
+                    let (key, r) = Self::parse_nested(rest, depth + 1)?;
+                    let (val, r) = Self::parse_nested(r, depth + 1)?;
+                    items.push((key, val));
+                    rest = r;
+                }
+
+                Self::Dict(items)
+            },
+            _ => return Err(import_error("invalid tnetstring type")),
+        };
+
+        Ok((val, &data[end + 1..]))
+    }
+
+    /// Look up a key in a dictionary.
+    fn get(&self, key: &str) -> Option<&Self> {
+        match self {
+            Self::Dict(items) => items.iter()
+                .find(|(k, _)| k.as_str() == Some(key))
+                .map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    /// Get the value of a string; mitmproxy stores most text as bytes.
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::Str(s) => Some(s.as_str()),
+            Self::Bytes(b) => std::str::from_utf8(b).ok(),
+            _ => None,
+        }
+    }
+
+    fn as_int(&self) -> Option<i64> {
+        match self {
+            Self::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_tnetstrings() {
+        let data = b"39:5:hello,3:123#1:a;4:true!1:n;0:~1:l;0:]}rest";
+        let (val, rest) = TNetString::parse(data).unwrap();
+
+        assert_eq!(rest, b"rest");
+        assert_eq!(val.get("hello"), Some(&TNetString::Int(123)));
+        assert_eq!(val.get("a"), Some(&TNetString::Bool(true)));
+        assert_eq!(val.get("n"), Some(&TNetString::Null));
+        assert_eq!(val.get("l"), Some(&TNetString::List(vec![])));
+        assert!(val.get("b").is_none());
+
+        assert!(TNetString::parse(b"5:abc,").is_err());
+    }
+
+    #[test]
+    fn reject_malformed_tnetstrings() {
+        // The length would overflow when added to the payload's offset.
+        let data = format!("{}:abc,", usize::MAX);
+        assert!(matches!(
+            TNetString::parse(data.as_bytes()),
+            Err(VcrError::Import(_))
+        ));
+
+        let mut nested = "0:]".to_owned();
+        for _ in 0..MAX_DEPTH {
+            nested = format!("{}:{}]", nested.len(), nested);
+        }
+        assert!(TNetString::parse(nested.as_bytes()).is_ok());
+
+        let nested = format!("{}:{}]", nested.len(), nested);
+        assert!(matches!(
+            TNetString::parse(nested.as_bytes()),
+            Err(VcrError::Import(_))
+        ));
+    }
+}
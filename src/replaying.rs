@@ -0,0 +1,778 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! How recorded responses are replayed: the middleware's replay options and
+//! their builders.
+
+#[cfg(feature = "response-schema")]
+use std::path::Path;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+#[cfg(feature = "response-schema")]
+use async_std::fs;
+
+use crate::{
+    Responder,
+    VcrError,
+    VcrMiddleware,
+    VcrMode,
+    VcrRequest,
+    VcrResponse,
+    CASSETTES,
+};
+
+
+/// How a middleware replays recorded responses.
+#[derive(Clone, Default)]
+pub(crate) struct ReplayOptions {
+    pub(crate) response_templating: bool,
+    pub(crate) record_on_replay_miss: bool,
+    pub(crate) forbid_network: bool,
+    pub(crate) allowed_hosts: Vec<String>,
+    pub(crate) default_response: Option<VcrResponse>,
+    pub(crate) jitter: Option<Duration>,
+    /// The size of each chunk of a replayed body, and the delay between them.
+    pub(crate) replay_chunking: Option<(usize, Duration)>,
+    pub(crate) responder: Option<Arc<Responder>>,
+    pub(crate) strict_order: bool,
+    pub(crate) echo_headers: Vec<String>,
+    #[cfg(feature = "response-schema")]
+    pub(crate) response_schema: Option<Arc<jsonschema::Validator>>,
+}
+
+impl VcrMiddleware {
+    /// Set whether to fill in placeholders in replayed response bodies.
+    ///
+    /// When enabled, these placeholders in a string body are replaced with
+    /// the values of the request being replayed:
+    ///
+    /// - `{{request.method}}`, `{{request.url}}`, `{{request.path}}`, and
+    ///   `{{request.body}}`;
+    /// - `{{request.query.NAME}}`, the value of the query parameter `NAME`;
+    /// - `{{request.headers.NAME}}`, the values of the header `NAME`.
+    ///
+    /// A query parameter or header the request doesn't send is replaced with
+    /// nothing; other text in braces is left as recorded.
+    pub fn with_response_templating(mut self, templating: bool) -> Self {
+        self.core.replay_options.response_templating = templating;
+        self
+    }
+
+    /// Validate replayed JSON response bodies against a JSON Schema.
+    ///
+    /// A string body that is valid JSON but doesn't conform to the schema fails
+    /// the request with [VcrError::SchemaViolation], so cassettes that no
+    /// longer match an API's contract are noticed. Other bodies are not
+    /// checked. Fails if `schema` is not a valid schema.
+    #[cfg(feature = "response-schema")]
+    pub fn with_response_schema(mut self, schema: &serde_json::Value)
+    -> Result<Self, VcrError> {
+        let validator = jsonschema::validator_for(schema)
+            .map_err(|e| VcrError::InvalidSchema(e.to_string()))?;
+
+        self.core.replay_options.response_schema = Some(Arc::new(validator));
+        Ok(self)
+    }
+
+    /// Validate replayed JSON response bodies against the JSON Schema in the
+    /// given file; see [VcrMiddleware::with_response_schema].
+    #[cfg(feature = "response-schema")]
+    pub async fn with_response_schema_file<P>(self, path: P)
+    -> Result<Self, VcrError>
+        where P: AsRef<Path>,
+    {
+        let schema = serde_json::from_str(
+            &fs::read_to_string(path.as_ref()).await?
+        )?;
+
+        self.with_response_schema(&schema)
+    }
+
+    /// Copy the given headers from each request into its replayed response.
+    ///
+    /// The request's values replace any recorded in the response, so headers
+    /// a server echoes (such as a correlation ID) match the live request.
+    /// Headers the request doesn't send are served as recorded.
+    pub fn with_echo_headers(mut self, names: &[&str]) -> Self {
+        self.core.replay_options.echo_headers = names.iter()
+            .map(|name| name.to_ascii_lowercase())
+            .collect();
+        self
+    }
+
+    /// Set whether requests must arrive in the order they were recorded.
+    ///
+    /// When enabled, each request while replaying is only compared with the
+    /// interaction after the one that was last replayed, so a request made out
+    /// of order fails with a diff against the request that was expected.
+    pub fn with_strict_order(mut self, strict: bool) -> Self {
+        self.core.replay_options.strict_order = strict;
+        self
+    }
+
+    /// Skip the first `index` interactions of the cassette while replaying in
+    /// order.
+    ///
+    /// This positions the cursor of [VcrMode::Stub] and of
+    /// [VcrMiddleware::with_strict_order] so the first interaction replayed is
+    /// the one at `index`, letting a focused test replay only part of a long
+    /// cassette. Other replays are unaffected. This must be set after
+    /// [VcrMiddleware::with_variant]; it fails if the cassette has fewer than
+    /// `index` interactions.
+    pub async fn with_start_index(self, index: usize)
+    -> Result<Self, VcrError> {
+        let mode = self.core.mode;
+
+        if mode != VcrMode::Replay && mode != VcrMode::Stub {
+            return Ok(self);
+        }
+
+        self.core.load_store().await?;
+
+        let cassettes = CASSETTES.read().await;
+        let len = match cassettes.get(&self.core.key) {
+            Some(session) => session.read().await.as_ref()
+                .map(|session| session.requests.len()),
+            None => None,
+        }.ok_or_else(|| VcrError::MissingSession(self.core.file.clone()))?;
+        drop(cassettes);
+
+        if index > len {
+            return Err(VcrError::StartIndexOutOfBounds {
+                cassette: self.core.file.clone(),
+                index,
+                len,
+            });
+        }
+
+        *self.core.next_in_order.lock().unwrap() = index;
+        *self.core.next_stub.lock().unwrap() = index;
+        Ok(self)
+    }
+
+    /// Set whether to record requests missing from the cassette while
+    /// replaying.
+    ///
+    /// When enabled, a request that doesn't match any recorded request is sent
+    /// to the server, and the interaction is appended to the cassette and
+    /// replayed for later matching requests.
+    pub fn with_record_on_replay_miss(mut self, record: bool) -> Self {
+        self.core.replay_options.record_on_replay_miss = record;
+        self
+    }
+
+    /// Set whether to forbid requests from reaching the network while
+    /// replaying.
+    ///
+    /// When enabled, requests that [VcrMiddleware::with_record_on_replay_miss]
+    /// would send to the server fail with [VcrError::NetworkForbidden] unless
+    /// their host was allowed by [VcrMiddleware::with_allowed_host].
+    pub fn with_forbid_network(mut self, forbid: bool) -> Self {
+        self.core.replay_options.forbid_network = forbid;
+        self
+    }
+
+    /// Allow requests to the given host (case-insensitive) to reach the network
+    /// despite [VcrMiddleware::with_forbid_network].
+    pub fn with_allowed_host<S: Into<String>>(mut self, host: S) -> Self {
+        self.core.replay_options.allowed_hosts.push(host.into());
+        self
+    }
+
+    /// Replay the given response for requests that are not in the cassette,
+    /// instead of failing them.
+    ///
+    /// Unlike [VcrMiddleware::with_record_on_replay_miss], unmatched requests
+    /// are never sent to the server; if both are set, unmatched requests are
+    /// recorded.
+    pub fn with_default_response(mut self, response: VcrResponse) -> Self {
+        self.core.replay_options.default_response = Some(response);
+        self
+    }
+
+    /// Set the function that creates responses in [VcrMode::Programmatic].
+    pub fn with_responder<F>(mut self, responder: F) -> Self
+        where F: Fn(&VcrRequest) -> VcrResponse + Send + Sync + 'static
+    {
+        self.core.replay_options.responder = Some(Arc::new(responder));
+        self
+    }
+
+    /// Replay response bodies in chunks of at most `size` bytes, waiting for
+    /// `delay` after each chunk.
+    ///
+    /// Each read of a replayed body returns no more than one chunk, so clients
+    /// that read bodies incrementally can be tested as though the body were
+    /// streamed from a server.
+    pub fn with_replay_chunking(mut self, size: usize, delay: Duration)
+    -> Self {
+        self.core.replay_options.replay_chunking = Some((size.max(1), delay));
+        self
+    }
+
+    /// Delay each replayed response by a random duration up to `max`.
+    ///
+    /// The jitter is added to any delay recorded in the cassette. The random
+    /// durations are seeded from the time unless a seed is set with
+    /// [VcrMiddleware::with_jitter_seed].
+    pub fn with_jitter(mut self, max: Duration) -> Self {
+        self.core.replay_options.jitter = Some(max);
+        self
+    }
+
+    /// Seed the random durations of [VcrMiddleware::with_jitter], so that the
+    /// same sequence of delays is used each time.
+    pub fn with_jitter_seed(mut self, seed: u64) -> Self {
+        self.core.jitter_rng = Arc::new(Mutex::new(seed));
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[cfg(feature = "yaml")]
+    #[async_std::test]
+    async fn delay_replay_per_header_directive() -> Result<(), VcrError> {
+        let client = surf::Client::new()
+            .with(VcrMiddleware::new(
+                VcrMode::Replay,
+                "test-sessions/delayed.yml"
+            ).await?);
+
+        let start = std::time::Instant::now();
+
+        let req = surf::get("https://example.com/slow").build();
+        let res = client.send(req).await.unwrap();
+
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(res.header("X-Vcr-Delay-Ms").is_none());
+        assert!(res.header("X-some-header").is_some());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "yaml")]
+    #[async_std::test]
+    async fn replay_templated_responses() -> Result<(), VcrError> {
+        let path = "test-sessions/record-templates.yml";
+        let _ = async_std::fs::remove_file(path).await;
+
+        let server = VcrMiddleware::new(
+            VcrMode::Programmatic,
+            "test-sessions/unused.yml"
+        ).await?
+            .with_responder(|req| {
+                let id = req.headers.get("x-request-id").unwrap().join("");
+
+                VcrResponse {
+                    status: StatusCode::Ok,
+                    version: None,
+                    headers: HashMap::new(),
+                    body: Body::Str(format!("{{\"id\":\"{}\"}}", id)),
+                    body_hash: None,
+                }
+            });
+
+        let client = surf::Client::new()
+            .with(
+                VcrMiddleware::new(VcrMode::Record, path).await?
+                    .with_response_template_extractor(|req, res| {
+                        let id = req.headers["x-request-id"].join("");
+                        let body = res.body.as_bytes();
+                        let body = String::from_utf8_lossy(body)
+                            .replace(&id, "{{request.headers.X-Request-Id}}");
+                        res.body = Body::Str(body);
+                    })
+            )
+            .with(server);
+
+        let req = |id: &str| surf::get("https://example.com/orders")
+            .header("X-Request-Id", id)
+            .build();
+
+        let mut res = client.send(req("42")).await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), r#"{"id":"42"}"#);
+
+        let replay = |templating| async move {
+            Ok::<_, VcrError>(surf::Client::new().with(
+                VcrMiddleware::new(VcrMode::Replay, path).await?
+                    .with_presence_only_headers(&["x-request-id"])
+                    .with_response_templating(templating)
+            ))
+        };
+
+        let client = replay(true).await?;
+        let mut res = client.send(req("7")).await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), r#"{"id":"7"}"#);
+
+        let client = replay(false).await?;
+        let mut res = client.send(req("7")).await.unwrap();
+        assert_eq!(
+            res.body_string().await.unwrap(),
+            r#"{"id":"{{request.headers.X-Request-Id}}"}"#
+        );
+
+        Ok(())
+    }
+
+    #[cfg(feature = "yaml")]
+    #[async_std::test]
+    async fn record_replay_misses() -> Result<(), VcrError> {
+        let path = "test-sessions/record-misses.yml";
+        async_std::fs::copy("test-sessions/whitespace.yml", path).await?;
+
+        let req = || surf::get("https://example.com")
+            .header("X-some-header", "another hello")
+            .header("Content-Type", "application/octet-stream")
+            .header("session-key", "00112233445566778899AABBCCDDEEFF")
+            .build();
+
+        let vcr = VcrMiddleware::new(VcrMode::Replay, path).await?
+            .with_record_on_replay_miss(true);
+
+        let client = surf::Client::new()
+            .with(vcr.clone())
+            .with(
+                VcrMiddleware::new(VcrMode::Replay, "test-sessions/simple.yml")
+                    .await?
+            );
+
+        let res = client.send(req()).await.unwrap();
+        assert_eq!(
+            res.ext::<ReplayedInteraction>().unwrap().cassette,
+            Path::new("test-sessions/simple.yml")
+        );
+
+        // The new interaction is replayed without reaching the "server".
+        let client = surf::Client::new().with(vcr);
+        let res = client.send(req()).await.unwrap();
+
+        assert_eq!(res.ext::<ReplayedInteraction>().unwrap().index, 1);
+        assert_eq!(Cassette::load(path).await?.len(), 2);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "yaml")]
+    #[async_std::test]
+    async fn replay_default_response_for_misses() -> Result<(), VcrError> {
+        let vcr = VcrMiddleware::new(
+            VcrMode::Replay,
+            "test-sessions/variant.yml"
+        ).await?
+            .with_default_response(VcrResponse {
+                status: StatusCode::NotFound,
+                version: None,
+                headers: HashMap::new(),
+                body: Body::Str("Not recorded".into()),
+                body_hash: None,
+            });
+        let client = surf::Client::new().with(vcr);
+
+        let req = surf::get("https://example.com/nowhere").build();
+        let mut res = client.send(req).await.unwrap();
+
+        assert_eq!(res.status(), StatusCode::NotFound);
+        assert_eq!(res.body_string().await.unwrap(), "Not recorded");
+        assert!(res.ext::<ReplayedInteraction>().is_none());
+
+        let req = surf::get("https://example.com/widgets").build();
+        let mut res = client.send(req).await.unwrap();
+
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res.body_string().await.unwrap(), "base widgets");
+
+        Ok(())
+    }
+
+    #[cfg(feature = "yaml")]
+    #[async_std::test]
+    async fn replay_seeded_jitter() -> Result<(), VcrError> {
+        let max = Duration::from_millis(20);
+        let vcr = || async {
+            VcrMiddleware::new(VcrMode::Replay, "test-sessions/variant.yml")
+                .await
+                .map(|vcr| vcr.with_jitter(max).with_jitter_seed(42))
+        };
+
+        let (first, second) = (vcr().await?, vcr().await?);
+        let delays = (0..10).map(|_| first.next_jitter().unwrap())
+            .collect::<Vec<_>>();
+
+        assert!(delays.iter().all(|d| *d <= max));
+        assert!(delays.windows(2).any(|w| w[0] != w[1]));
+        assert!(delays.iter().all(|d| *d == second.next_jitter().unwrap()));
+
+        let client = surf::Client::new().with(vcr().await?);
+        let req = surf::get("https://example.com/widgets").build();
+        let mut res = client.send(req).await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "base widgets");
+
+        Ok(())
+    }
+
+    #[cfg(feature = "yaml")]
+    #[async_std::test]
+    async fn replay_body_faults() -> Result<(), VcrError> {
+        use async_std::io::ReadExt;
+
+        let client = surf::Client::new().with(VcrMiddleware::new(
+            VcrMode::Replay,
+            "test-sessions/faults.yml"
+        ).await?);
+
+        let mut res = client.get("https://example.com/truncated").await
+            .unwrap();
+        assert!(res.header(FAULT_HEADER).is_none());
+        assert_eq!(res.body_string().await.unwrap(), "Hello");
+
+        // The bytes before the disconnect are delivered, then reading fails.
+        let mut res = client.get("https://example.com/dropped").await
+            .unwrap();
+        let mut body = res.take_body();
+
+        let mut received = vec![];
+        let mut buf = [0; 64];
+
+        let err = loop {
+            match body.read(&mut buf).await {
+                Ok(0) => panic!("The body ended without disconnecting"),
+                Ok(n) => received.extend_from_slice(&buf[..n]),
+                Err(e) => break e,
+            }
+        };
+
+        assert_eq!(received, b"Hello");
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "yaml")]
+    #[async_std::test]
+    async fn replay_in_strict_order() -> Result<(), VcrError> {
+        let client = || async {
+            Ok::<_, VcrError>(surf::Client::new().with(VcrMiddleware::new(
+                VcrMode::Replay,
+                "test-sessions/variant.yml"
+            ).await?.with_strict_order(true)))
+        };
+
+        let ordered = client().await?;
+        ordered.get("https://example.com/widgets").await.unwrap();
+        ordered.get("https://example.com/gadgets").await.unwrap();
+        assert!(ordered.get("https://example.com/widgets").await.is_err());
+
+        let reordered = client().await?;
+        let err = reordered.get("https://example.com/gadgets").await
+            .unwrap_err();
+
+        match err.downcast_ref::<VcrError>() {
+            Some(VcrError::Lookup { diff: Some(diff), .. }) => {
+                assert!(diff.contains("- url: https://example.com/widgets"));
+            },
+            e => panic!("Unexpected error: {:?}", e),
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "yaml")]
+    #[async_std::test]
+    async fn replay_in_order_from_start_index() -> Result<(), VcrError> {
+        let path = "test-sessions/variant.yml";
+
+        let ordered = surf::Client::new().with(
+            VcrMiddleware::new(VcrMode::Replay, path).await?
+                .with_strict_order(true)
+                .with_start_index(1).await?
+        );
+        assert!(ordered.get("https://example.com/widgets").await.is_err());
+        ordered.get("https://example.com/gadgets").await.unwrap();
+
+        let stubbed = surf::Client::new().with(
+            VcrMiddleware::new(VcrMode::Stub, path).await?
+                .with_start_index(1).await?
+        );
+        let mut res = stubbed.get("https://example.com/x").await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "base gadgets");
+
+        match VcrMiddleware::new(VcrMode::Replay, path).await?
+            .with_start_index(3).await
+        {
+            Err(VcrError::StartIndexOutOfBounds { index: 3, len: 2, .. }) => {},
+            Err(e) => panic!("Unexpected error: {:?}", e),
+            Ok(_) => panic!("Start index past the end was accepted"),
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "yaml")]
+    #[async_std::test]
+    async fn echo_request_headers() -> Result<(), VcrError> {
+        let client = surf::Client::new().with(VcrMiddleware::new(
+            VcrMode::Replay,
+            "test-sessions/simple.yml"
+        ).await?.with_echo_headers(&["X-Some-Header", "X-Correlation-Id"]));
+
+        let req = surf::get("https://example.com")
+            .header("X-some-header", "another hello")
+            .header("Content-Type", "application/octet-stream")
+            .header("session-key", "00112233445566778899AABBCCDDEEFF")
+            .build();
+
+        // The response was recorded with "another goodbye".
+        let res = client.send(req).await.unwrap();
+        assert_eq!(res["x-some-header"], "another hello");
+        assert!(res.header("x-correlation-id").is_none());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "response-schema")]
+    #[async_std::test]
+    async fn validate_responses_against_schema() -> Result<(), VcrError> {
+        let schema = serde_json::json!({
+            "type": "object",
+            "required": ["id"],
+            "properties": { "id": { "type": "integer" } },
+        });
+
+        let client = surf::Client::new().with(VcrMiddleware::new(
+            VcrMode::Replay,
+            "test-sessions/json-response.yml"
+        ).await?.with_response_schema(&schema)?);
+        assert!(client.get("https://example.com/widgets/5").await.is_ok());
+
+        let schema = serde_json::json!({ "required": ["email"] });
+        let client = surf::Client::new().with(VcrMiddleware::new(
+            VcrMode::Replay,
+            "test-sessions/json-response.yml"
+        ).await?.with_response_schema(&schema)?);
+
+        let err = client.get("https://example.com/widgets/5").await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<VcrError>(),
+            Some(VcrError::SchemaViolation { index: 0, .. })
+        ));
+
+        let invalid = serde_json::json!({ "type": 12 });
+        assert!(VcrMiddleware::new(
+            VcrMode::Replay,
+            "test-sessions/json-response.yml"
+        ).await?.with_response_schema(&invalid).is_err());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "yaml")]
+    #[async_std::test]
+    async fn refresh_expired_interactions() -> Result<(), VcrError> {
+        let path = "test-sessions/record-expired.yml";
+        async_std::fs::copy("test-sessions/expired.yml", path).await?;
+
+        let vcr = VcrMiddleware::new(VcrMode::Replay, path).await?
+            .with_record_on_replay_miss(true)
+            .with_interaction_ttl(|_| Some(Duration::from_secs(3600)));
+        let client = surf::Client::new()
+            .with(vcr.clone())
+            .with(VcrMiddleware::new(
+                VcrMode::Replay,
+                "test-sessions/variant.yml"
+            ).await?);
+
+        let mut res = client.get("https://example.com/widgets").await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "base widgets");
+
+        // The refreshed interaction is replayed without its expiry.
+        let client = surf::Client::new().with(vcr);
+        let mut res = client.get("https://example.com/widgets").await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "base widgets");
+        assert!(res.header(EXPIRES_HEADER).is_none());
+
+        let cassette = Cassette::load(path).await?;
+        assert_eq!(cassette.len(), 2);
+        let (_, refreshed) = cassette.interactions().last().unwrap();
+        assert!(! is_expired(refreshed));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "yaml")]
+    #[async_std::test]
+    async fn replay_expired_interactions_offline() -> Result<(), VcrError> {
+        // Stands in for a server that can't be reached.
+        fn unreachable<'a>(_: Request, _: Client, _: Next<'a>)
+        -> futures::future::BoxFuture<'a, surf::Result<Response>> {
+            Box::pin(async {
+                Err(surf::Error::from_str(StatusCode::BadGateway, "offline"))
+            })
+        }
+
+        let path = "test-sessions/expired.yml";
+
+        let vcr = VcrMiddleware::new(VcrMode::Replay, path).await?
+            .with_record_on_replay_miss(true)
+            .with_forbid_network(true);
+        let client = surf::Client::new().with(vcr.clone());
+
+        let mut res = client.get("https://example.com/widgets").await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "stale widgets");
+        assert_eq!(vcr.stats().await.expired, 1);
+
+        let vcr = VcrMiddleware::new(VcrMode::Replay, path).await?
+            .with_record_on_replay_miss(true);
+        let client = surf::Client::new()
+            .with(vcr.clone())
+            .with(unreachable);
+
+        let mut res = client.get("https://example.com/widgets").await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "stale widgets");
+
+        // Requests without an expired interaction to fall back on still fail.
+        let err = client.get("https://example.com/gadgets").await.unwrap_err();
+        assert_eq!(err.status(), StatusCode::BadGateway);
+
+        let stats = vcr.stats().await;
+        assert_eq!((stats.matched, stats.misses, stats.expired), (1, 1, 1));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "yaml")]
+    #[async_std::test]
+    async fn replay_bodies_in_chunks() -> Result<(), VcrError> {
+        use async_std::io::ReadExt;
+        use std::time::Instant;
+
+        let delay = Duration::from_millis(20);
+        let client = surf::Client::new().with(
+            VcrMiddleware::new(VcrMode::Replay, "test-sessions/variant.yml")
+                .await?
+                .with_replay_chunking(5, delay)
+        );
+
+        let mut res = client.get("https://example.com/widgets").await.unwrap();
+        let mut body = res.take_body();
+        let start = Instant::now();
+
+        let mut chunks = vec![];
+        let mut buf = [0; 64];
+
+        loop {
+            let n = body.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            chunks.push(String::from_utf8(buf[..n].to_vec()).unwrap());
+        }
+
+        assert_eq!(chunks, vec!["base ", "widge", "ts"]);
+        assert!(start.elapsed() >= delay * 2);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn respond_programmatically() -> Result<(), VcrError> {
+        let response = |status, body: &str| VcrResponse {
+            status,
+            version: None,
+            headers: HashMap::new(),
+            body: Body::Str(body.into()),
+            body_hash: None,
+        };
+
+        let vcr = VcrMiddleware::new(
+            VcrMode::Programmatic,
+            "test-sessions/unused.yml"
+        ).await?
+            .with_responder(move |req| {
+                response(StatusCode::Ok, &format!("echo {}", req.url.path()))
+            });
+        let client = surf::Client::new().with(vcr.clone());
+
+        vcr.push_response(response(StatusCode::Created, "queued"));
+
+        let mut res = client.get("https://example.com/a").await.unwrap();
+        assert_eq!(res.status(), StatusCode::Created);
+        assert_eq!(res.body_string().await.unwrap(), "queued");
+
+        let mut res = client.get("https://example.com/b").await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "echo /b");
+
+        let client = surf::Client::new()
+            .with(VcrMiddleware::new(
+                VcrMode::Programmatic,
+                "test-sessions/unused.yml"
+            ).await?);
+        assert!(client.get("https://example.com").await.is_err());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "yaml")]
+    #[async_std::test]
+    async fn forbid_network_while_replaying() -> Result<(), VcrError> {
+        let path = "test-sessions/record-forbidden.yml";
+        async_std::fs::copy("test-sessions/variant.staging.yml", path).await?;
+
+        let vcr = VcrMiddleware::new(VcrMode::Replay, path).await?
+            .with_record_on_replay_miss(true)
+            .with_forbid_network(true)
+            .with_allowed_host("Example.com");
+        let client = surf::Client::new()
+            .with(vcr)
+            .with(VcrMiddleware::new(
+                VcrMode::Replay,
+                "test-sessions/variant.yml"
+            ).await?);
+
+        let mut res = client.get("https://example.com/gadgets").await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "base gadgets");
+
+        let err = client.get("https://other.example.com/gadgets").await
+            .unwrap_err();
+        assert_eq!(err.status(), StatusCode::Forbidden);
+        assert_eq!(interaction_count(path).await?, 2);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "yaml")]
+    #[async_std::test]
+    async fn replay_delays_within_client_timeout() -> Result<(), VcrError> {
+        use std::convert::TryFrom;
+
+        let client = |timeout| async move {
+            let config = surf::Config::new()
+                .set_timeout(Some(Duration::from_millis(timeout)));
+
+            Ok::<_, VcrError>(surf::Client::try_from(config).unwrap()
+                .with(VcrMiddleware::new(
+                    VcrMode::Replay,
+                    "test-sessions/delayed.yml"
+                ).await?))
+        };
+
+        // The client's timeout error is enough to show the delay was cut
+        // short; timing the request would make the test depend on the load
+        // of the machine running it.
+        let err = client(10).await?.get("https://example.com/slow").await
+            .unwrap_err();
+        assert_eq!(err.status(), StatusCode::RequestTimeout);
+
+        assert!(client(1000).await?.get("https://example.com/slow").await
+            .is_ok());
+
+        Ok(())
+    }
+}
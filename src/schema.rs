@@ -0,0 +1,36 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A JSON Schema of the cassette format.
+
+use schemars::{schema::RootSchema, schema_for, JsonSchema};
+use serde::Serialize;
+
+use crate::SerdeWrapper;
+
+
+/// Generate a JSON Schema describing JSON cassettes.
+///
+/// The schema also describes each document of a YAML cassette, for editors
+/// that validate YAML against JSON Schemas.
+///
+/// ```
+/// let schema = surf_vcr::cassette_schema();
+/// let text = serde_json::to_string_pretty(&schema).unwrap();
+/// assert!(text.contains("Request"));
+/// ```
+pub fn cassette_schema() -> RootSchema {
+    schema_for!(Vec<Document>)
+}
+
+/// A document of a cassette: its metadata, or an interaction.
+///
+/// This is never constructed; it only describes the on-disk layout.
+#[derive(Serialize, JsonSchema)]
+#[serde(untagged)]
+#[allow(dead_code)]
+enum Document {
+    Interaction(Box<SerdeWrapper>, Box<SerdeWrapper>),
+    Metadata(Box<SerdeWrapper>),
+}
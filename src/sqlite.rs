@@ -0,0 +1,165 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A cassette store in a SQLite database.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use rusqlite::{params, Connection};
+
+use crate::{
+    store::{store_key, CassetteStore, StoreFuture},
+    VcrError,
+    VcrRequest,
+    VcrResponse,
+};
+
+
+/// A [CassetteStore] that keeps its interactions in a table of a SQLite
+/// database, keyed by a hash of their requests.
+///
+/// Recording inserts a row per interaction rather than rewriting a cassette,
+/// and [CassetteStore::find] is an indexed query on the key, so stores can
+/// grow to many thousands of interactions without replaying reading them
+/// all. Requests and responses are stored
+/// as JSON.
+///
+/// ```no_run
+/// # async fn runtest() -> Result<(), surf_vcr::VcrError> {
+/// use surf_vcr::{SqliteStore, VcrMiddleware, VcrMode};
+///
+/// let store = SqliteStore::open("tests/sessions/api.sqlite")?;
+/// let vcr = VcrMiddleware::from_store(VcrMode::Replay, store).await?;
+/// # Ok(()) }
+/// ```
+///
+/// The database is queried synchronously; SQLite reads and writes single rows
+/// quickly enough that this doesn't hold up the executor.
+pub struct SqliteStore {
+    path: PathBuf,
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Open the SQLite database at `path`, creating it (and its table of
+    /// interactions) if necessary.
+    pub fn open<P>(path: P) -> Result<Self, VcrError>
+        where P: Into<PathBuf>,
+    {
+        let path = path.into();
+        let conn = Connection::open(&path)?;
+
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS interactions (
+                id INTEGER PRIMARY KEY,
+                key TEXT NOT NULL,
+                request TEXT NOT NULL,
+                response TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS interactions_by_key
+                ON interactions (key);"
+        )?;
+
+        Ok(Self { path, conn: Mutex::new(conn) })
+    }
+
+    /// Read the interactions selected by `sql`, whose parameters are
+    /// `params`, along with their positions in the table.
+    ///
+    /// The query selects a row's position, request, and response.
+    fn query(&self, sql: &str, params: &[&dyn rusqlite::ToSql])
+    -> Result<Vec<(usize, VcrRequest, VcrResponse)>, VcrError> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare_cached(sql)?;
+
+        let rows = statement.query_map(params, |row| {
+            Ok((
+                row.get::<_, usize>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+            ))
+        })?;
+
+        let mut interactions = vec![];
+
+        for row in rows {
+            let (pos, request, response) = row?;
+
+            interactions.push((
+                pos,
+                serde_json::from_str(&request)?,
+                serde_json::from_str(&response)?,
+            ));
+        }
+
+        Ok(interactions)
+    }
+}
+
+impl CassetteStore for SqliteStore {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn load(&self) -> StoreFuture<'_, Vec<(VcrRequest, VcrResponse)>> {
+        Box::pin(async move {
+            let interactions = self.query(
+                "SELECT 0, request, response FROM interactions ORDER BY id",
+                &[]
+            )?;
+
+            Ok(interactions.into_iter()
+                .map(|(_, request, response)| (request, response))
+                .collect())
+        })
+    }
+
+    fn append<'a>(&'a self, request: &'a VcrRequest, response: &'a VcrResponse)
+    -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            let key = store_key(request);
+            let request = serde_json::to_string(request)?;
+            let response = serde_json::to_string(response)?;
+
+            self.conn.lock().unwrap().execute(
+                "INSERT INTO interactions (key, request, response)
+                    VALUES (?1, ?2, ?3)",
+                params![key, request, response]
+            )?;
+
+            Ok(())
+        })
+    }
+
+    fn find<'a>(&'a self, request: &'a VcrRequest)
+    -> StoreFuture<'a, Vec<(usize, VcrRequest, VcrResponse)>> {
+        Box::pin(async move {
+            // A row's position is the number of rows inserted before it.
+            self.query(
+                "SELECT
+                    (SELECT COUNT(*) FROM interactions AS earlier
+                        WHERE earlier.id < interactions.id),
+                    request,
+                    response
+                FROM interactions WHERE key = ?1 ORDER BY id",
+                &[&store_key(request)]
+            )
+        })
+    }
+
+    fn count(&self) -> StoreFuture<'_, usize> {
+        Box::pin(async move {
+            let conn = self.conn.lock().unwrap();
+
+            Ok(conn.query_row(
+                "SELECT COUNT(*) FROM interactions",
+                [],
+                |row| row.get(0)
+            )?)
+        })
+    }
+}
@@ -0,0 +1,83 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Backends that keep recorded interactions somewhere other than a cassette
+//! file.
+
+use std::{future::Future, path::Path, pin::Pin};
+
+use crate::{VcrError, VcrRequest, VcrResponse};
+
+
+/// The future returned by the methods of a [CassetteStore].
+pub type StoreFuture<'a, T> =
+    Pin<Box<dyn Future<Output = Result<T, VcrError>> + Send + 'a>>;
+
+/// A backend that records and replays interactions in place of a cassette
+/// file; see [VcrMiddleware::from_store].
+///
+/// Requests are looked up with [CassetteStore::find] while they must match
+/// the recorded requests exactly; the store's interactions are only loaded in
+/// full when the middleware's matching needs them all (or a request isn't
+/// found), and then only once. Each interaction is added to the store as it's
+/// recorded. The methods return boxed futures, so stores don't need
+/// `async_trait`.
+///
+/// [VcrMiddleware::from_store]: crate::VcrMiddleware::from_store
+pub trait CassetteStore: Send + Sync {
+    /// The path that identifies the store.
+    ///
+    /// Middlewares for the same path share their interactions, as they do for
+    /// a cassette file, and errors name the store by its path.
+    fn path(&self) -> &Path;
+
+    /// Load every interaction in the store, in the order they were recorded.
+    fn load(&self) -> StoreFuture<'_, Vec<(VcrRequest, VcrResponse)>>;
+
+    /// Add an interaction to the store.
+    fn append<'a>(&'a self, request: &'a VcrRequest, response: &'a VcrResponse)
+    -> StoreFuture<'a, ()>;
+
+    /// Find the interactions whose requests have the same method, URL, and
+    /// body as `request` (and so the same [VcrRequest::interaction_id]), in
+    /// the order they were recorded, along with their positions in the order
+    /// of [CassetteStore::load].
+    ///
+    /// This loads the whole store; stores that index their interactions
+    /// should replace it.
+    fn find<'a>(&'a self, request: &'a VcrRequest)
+    -> StoreFuture<'a, Vec<(usize, VcrRequest, VcrResponse)>> {
+        Box::pin(async move {
+            let key = store_key(request);
+
+            Ok(self.load().await?.into_iter()
+                .enumerate()
+                .filter(|(_, (recorded, _))| store_key(recorded) == key)
+                .map(|(pos, (recorded, response))| (pos, recorded, response))
+                .collect())
+        })
+    }
+
+    /// The number of interactions in the store.
+    ///
+    /// This loads the whole store; stores that can count their interactions
+    /// should replace it.
+    fn count(&self) -> StoreFuture<'_, usize> {
+        Box::pin(async move { Ok(self.load().await?.len()) })
+    }
+}
+
+impl std::fmt::Debug for dyn CassetteStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CassetteStore").field("path", &self.path()).finish()
+    }
+}
+
+/// The key of a request in a store: its interaction ID, ignoring any ID it
+/// was given.
+pub(crate) fn store_key(request: &VcrRequest) -> String {
+    let mut request = request.clone();
+    request.id = None;
+    request.interaction_id()
+}
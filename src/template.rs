@@ -0,0 +1,71 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Response templates filled in from the request being replayed.
+
+use crate::VcrRequest;
+
+
+/// Replace the `{{request...}}` placeholders in `template` with the values of
+/// `request`; see [VcrMiddleware::with_response_templating].
+///
+/// Placeholders that don't name a part of the request are left as they are.
+///
+/// [VcrMiddleware::with_response_templating]:
+///     crate::VcrMiddleware::with_response_templating
+pub(crate) fn render(template: &str, request: &VcrRequest) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            None => break,
+        };
+
+        out.push_str(&rest[..start]);
+
+        match lookup(rest[start+2..end].trim(), request) {
+            Some(value) => out.push_str(&value),
+            None => out.push_str(&rest[start..end+2]),
+        }
+
+        rest = &rest[end+2..];
+    }
+
+    out.push_str(rest);
+    out
+}
+
+/// Find the value of a placeholder's expression, such as
+/// `request.query.id`.
+fn lookup(expr: &str, request: &VcrRequest) -> Option<String> {
+    let field = expr.strip_prefix("request.")?;
+
+    if let Some(name) = field.strip_prefix("query.") {
+        let value = request.url.query_pairs()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.into_owned());
+
+        return Some(value.unwrap_or_default());
+    }
+
+    if let Some(name) = field.strip_prefix("headers.") {
+        let value = request.headers.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.join(", "));
+
+        return Some(value.unwrap_or_default());
+    }
+
+    match field {
+        "method" => Some(request.method.to_string()),
+        "url" => Some(request.url.to_string()),
+        "path" => Some(request.url.path().to_owned()),
+        "body" => Some(
+            String::from_utf8_lossy(request.body.as_bytes()).into_owned()
+        ),
+        _ => None,
+    }
+}
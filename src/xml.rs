@@ -0,0 +1,58 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Semantic comparison of XML bodies.
+
+use roxmltree::{Document, Node};
+
+
+/// Compare two XML documents by their content, or return `None` if either is
+/// not valid XML.
+pub(crate) fn bodies_match(recorded: &[u8], body: &[u8]) -> Option<bool> {
+    let recorded = Document::parse(std::str::from_utf8(recorded).ok()?).ok()?;
+    let body = Document::parse(std::str::from_utf8(body).ok()?).ok()?;
+
+    Some(canonical(recorded.root_element()) == canonical(body.root_element()))
+}
+
+/// An element with its attributes sorted and insignificant content removed.
+#[derive(Debug, PartialEq)]
+enum Canonical<'a> {
+    Element {
+        namespace: Option<&'a str>,
+        name: &'a str,
+        attributes: Vec<(Option<&'a str>, &'a str, &'a str)>,
+        children: Vec<Canonical<'a>>,
+    },
+    Text(&'a str),
+}
+
+fn canonical<'a>(node: Node<'a, '_>) -> Canonical<'a> {
+    let mut attributes = node.attributes()
+        .map(|attr| (attr.namespace(), attr.name(), attr.value()))
+        .collect::<Vec<_>>();
+    attributes.sort_unstable();
+
+    // Whitespace around text is insignificant, as are comments and processing
+    // instructions.
+    let children = node.children()
+        .filter_map(|child| {
+            if child.is_element() {
+                Some(canonical(child))
+            } else if child.is_text() {
+                let text = child.text().unwrap_or_default().trim();
+                if text.is_empty() { None } else { Some(Canonical::Text(text)) }
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    Canonical::Element {
+        namespace: node.tag_name().namespace(),
+        name: node.tag_name().name(),
+        attributes,
+        children,
+    }
+}